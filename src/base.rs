@@ -0,0 +1,153 @@
+use super::command::shell_quote;
+use super::packages;
+use super::packages::{Package, PackageManager};
+use super::remote::Remote;
+
+pub const DEFAULT_TIMEZONE: &str = "UTC";
+pub const DEFAULT_LOCALE: &str = "en_US.UTF-8";
+// Enough for cargo build or gem install jekyll on a 512mb droplet
+pub const DEFAULT_SWAP: &str = "1G";
+
+const SWAP_FILE: &str = "/swapfile";
+
+pub struct BaseOptions<'a> {
+    // Usually the droplet's DNS name
+    pub hostname: &'a str,
+    pub timezone: &'a str,
+    pub locale: &'a str,
+    // Size in MiB, no swap file when 0
+    pub swap_mb: u64,
+}
+
+// Sizes like 512M, 2G or plain megabytes
+pub fn parse_swap_size(size: &str) -> Result<u64, String> {
+    let invalid = || format!("Invalid swap size {}, expected e.g. 512M or 2G", size);
+    let size = size.trim();
+    let (number, multiplier) = match size.chars().last() {
+        Some('G') | Some('g') => (&size[..size.len() - 1], 1024),
+        Some('M') | Some('m') => (&size[..size.len() - 1], 1),
+        _ => (size, 1),
+    };
+    number.parse::<u64>().map(|n| n * multiplier).map_err(|_| invalid())
+}
+
+// Hostnames, timezones and locales all end up in shell commands and config files
+fn validate(what: &str, value: &str, extra: &[char]) -> Result<(), String> {
+    if !value.is_empty() && value.chars().all(|c| c.is_ascii_alphanumeric() || extra.contains(&c)) {
+        Ok(())
+    } else {
+        Err(format!("Invalid {}: {}", what, value))
+    }
+}
+
+impl<'a> BaseOptions<'a> {
+    pub fn validate(&self) -> Result<(), String> {
+        validate("hostname", self.hostname, &['.', '-'])?;
+        validate("timezone", self.timezone, &['/', '_', '-', '+'])?;
+        validate("locale", self.locale, &['.', '_', '-', '@'])
+    }
+}
+
+// Root commands setting the hostname, with /etc/hosts resolving it locally
+pub fn hostname_script(hostname: &str) -> String {
+    let short = hostname.split('.').next().unwrap_or(hostname);
+    format!("(hostnamectl set-hostname {0} || (echo {0} > /etc/hostname && hostname {0})) && \
+             sed -i '/^127\\.0\\.1\\.1[[:space:]]/d' /etc/hosts && echo '127.0.1.1 {0} {1}' >> /etc/hosts", hostname, short)
+}
+
+pub fn timezone_script(timezone: &str) -> String {
+    format!("[ -f /usr/share/zoneinfo/{0} ] && (timedatectl set-timezone {0} || (ln -sf /usr/share/zoneinfo/{0} /etc/localtime && echo {0} > /etc/timezone))", timezone)
+}
+
+// Root commands generating locale and making it the system default. musl has no locale data
+// to generate, so Alpine only gets LANG set.
+pub fn locale_script(manager: PackageManager, locale: &str) -> String {
+    match manager {
+        PackageManager::Apt => {
+            let charset = locale.split('.').nth(1).unwrap_or("UTF-8");
+            let line = format!("{} {}", locale, charset);
+            format!("DEBIAN_FRONTEND=noninteractive apt-get install -y locales && \
+                     (grep -qx {0} /etc/locale.gen || echo {0} >> /etc/locale.gen) && locale-gen && update-locale LANG={1}",
+                    shell_quote(&line), locale)
+        },
+        PackageManager::Dnf => {
+            let language = locale.split('_').next().unwrap_or(locale);
+            format!("dnf install -y glibc-langpack-{} && localectl set-locale LANG={}", language, locale)
+        },
+        PackageManager::Apk => format!("echo 'export LANG={}' > /etc/profile.d/breezyvps-locale.sh", locale),
+    }
+}
+
+// Root commands creating a swap file of size_mb, replacing one of another size, and enabling
+// it now and on boot
+pub fn swap_script(size_mb: u64) -> String {
+    format!("[ \"$(stat -c %s {file} 2>/dev/null)\" = \"{bytes}\" ] || {{ \
+             (swapoff {file} 2>/dev/null; rm -f {file}) && \
+             (fallocate -l {mb}M {file} || dd if=/dev/zero of={file} bs=1M count={mb}) && chmod 600 {file} && mkswap {file}; }} && \
+             (grep -q '^{file} ' /proc/swaps || swapon {file}) && \
+             (grep -q '^{file} ' /etc/fstab || echo '{file} none swap sw 0 0' >> /etc/fstab)",
+            file = SWAP_FILE, mb = size_mb, bytes = size_mb * 1024 * 1024)
+}
+
+// chrony keeps the clock in sync on every distro we support
+pub fn ntp_service(manager: PackageManager) -> &'static str {
+    match manager {
+        PackageManager::Apt => "chrony",
+        _ => "chronyd",
+    }
+}
+
+// Hostname, timezone, locale, swap and time sync for a fresh host
+pub fn configure_base(remote: &Remote, options: &BaseOptions) -> Result<(), String> {
+    options.validate()?;
+    let manager = PackageManager::detect(remote)?;
+    packages::install(remote, &[Package::Tzdata, Package::Chrony])?;
+
+    let mut steps = vec![
+        ("hostname", hostname_script(options.hostname)),
+        ("timezone", timezone_script(options.timezone)),
+        ("locale", locale_script(manager, options.locale)),
+        ("time sync", packages::enable_service_script(ntp_service(manager))),
+    ];
+    if options.swap_mb > 0 {
+        steps.push(("swap", swap_script(options.swap_mb)));
+    }
+    for (what, script) in steps {
+        info!("Setting {} on {}", what, remote.host);
+        let result = remote.run_root(&script);
+        if !result.success {
+            return Err(format!("Failed to set {} on {}:\n\n{}", what, remote.host, result.stderr));
+        }
+    }
+    println!("{} is {}, {} with {}, swap {}", remote.host, options.hostname, options.timezone, options.locale,
+             if options.swap_mb > 0 { format!("{}M", options.swap_mb) } else { "unchanged".to_string() });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_swap_sizes() {
+        assert_eq!(parse_swap_size("1G"), Ok(1024));
+        assert_eq!(parse_swap_size("512M"), Ok(512));
+        assert_eq!(parse_swap_size("0"), Ok(0));
+        assert!(parse_swap_size("1T").is_err());
+    }
+
+    #[test]
+    fn validates_names() {
+        let options = BaseOptions { hostname: "cloud.one.haus", timezone: "America/Los_Angeles", locale: "en_US.UTF-8", swap_mb: 0 };
+        assert!(options.validate().is_ok());
+        assert!(BaseOptions { timezone: "UTC; reboot", ..options }.validate().is_err());
+    }
+
+    #[test]
+    fn builds_base_scripts() {
+        assert!(hostname_script("cloud.one.haus").ends_with("echo '127.0.1.1 cloud.one.haus cloud' >> /etc/hosts"));
+        assert!(locale_script(PackageManager::Apt, "de_DE.ISO-8859-1").contains("echo 'de_DE.ISO-8859-1 ISO-8859-1' >> /etc/locale.gen"));
+        assert_eq!(locale_script(PackageManager::Dnf, "en_US.UTF-8"), "dnf install -y glibc-langpack-en && localectl set-locale LANG=en_US.UTF-8");
+        assert!(swap_script(1024).starts_with("[ \"$(stat -c %s /swapfile 2>/dev/null)\" = \"1073741824\" ]"));
+    }
+}
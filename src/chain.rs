@@ -5,8 +5,8 @@ use super::command;
 pub enum Item<'a> {
     FatalCommand(String),
     NonFatalCommand(String),
-    ResultMappedCommand(&'a Fn(&command::Result, String) -> String, String, bool),
-    ResultProcessor(&'a Fn(&command::Result) -> command::Result),
+    ResultMappedCommand(&'a dyn Fn(&command::Result, String) -> String, String, bool),
+    ResultProcessor(&'a dyn Fn(&command::Result) -> command::Result),
 }
 
 pub struct CommandChain<'a> {
@@ -15,6 +15,12 @@ pub struct CommandChain<'a> {
     pub result: Option<command::Result>
 }
 
+impl<'a> Default for CommandChain<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<'a> CommandChain<'a> {
 
     pub fn new() -> Self {
@@ -25,17 +31,17 @@ impl<'a> CommandChain<'a> {
         }
     }
 
-    pub fn result_proc(mut self, f: &'a Fn(&command::Result) -> command::Result) -> Self {
+    pub fn result_proc(mut self, f: &'a dyn Fn(&command::Result) -> command::Result) -> Self {
         self.commands.push(Item::ResultProcessor(f));
         self
     }
 
-    pub fn result_mapped_cmd(mut self, f: &'a Fn(&command::Result, String) -> String, command_string: &str) -> Self {
+    pub fn result_mapped_cmd(mut self, f: &'a dyn Fn(&command::Result, String) -> String, command_string: &str) -> Self {
         self.commands.push(Item::ResultMappedCommand(f, command_string.to_string(), true));
         self
     }
 
-    pub fn result_mapped_cmd_nonfatal(mut self, f: &'a Fn(&command::Result, String) -> String, command_string: &str) -> Self {
+    pub fn result_mapped_cmd_nonfatal(mut self, f: &'a dyn Fn(&command::Result, String) -> String, command_string: &str) -> Self {
         self.commands.push(Item::ResultMappedCommand(f, command_string.to_string(), false));
         self
     }
@@ -66,8 +72,8 @@ impl<'a> CommandChain<'a> {
     // Executes the chain and returns self, with vector reset
     pub fn execute(mut self) -> Self {
         for item in self.commands.iter() {
-            match item {
-                &Item::FatalCommand(ref s) => {
+            match *item {
+                Item::FatalCommand(ref s) => {
                     let result = CommandChain::run_command(s);
                     if !result.success {
                         self.result = Some(result);
@@ -75,24 +81,24 @@ impl<'a> CommandChain<'a> {
                     }
                     self.result = Some(result);
                 },
-                &Item::NonFatalCommand(ref s) => {
+                Item::NonFatalCommand(ref s) => {
                     let result = CommandChain::run_command(s);
                     self.result = Some(result);
                 },
-                &Item::ResultProcessor(f) => {
+                Item::ResultProcessor(f) => {
                     self.result = {
                         if let Some(ref mut curr_res) = self.result {
-                            Some(f(&curr_res))
+                            Some(f(curr_res))
                         } else {
                             warn!("Executing ResultProcessor with no current result is a no-op!");
                             None
                         }
                     };
                 },
-                &Item::ResultMappedCommand(f, ref s, is_fatal) => {
+                Item::ResultMappedCommand(f, ref s, is_fatal) => {
                     let mapped_command : String = {
                         if let Some(ref mut curr_res) = self.result {
-                            f(&curr_res, s.to_string())
+                            f(curr_res, s.to_string())
                         } else {
                             warn!("Executing ResultMappedCommand with no current result is a no-op!");
                             s.to_string()
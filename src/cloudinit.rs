@@ -0,0 +1,254 @@
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+use std::os::unix::fs::OpenOptionsExt;
+use serde_json;
+use toml;
+use super::command;
+use super::config;
+use super::remote::Remote;
+
+// What to do at first boot, read from a TOML file:
+//
+//     packages = ["nginx"]
+//     runcmd = ["systemctl enable --now nginx"]
+//
+//     [[users]]
+//     name = "deploy"
+//     sudo = true
+//     ssh_keys = ["~/.ssh/id_ed25519.pub"]
+//
+//     [[files]]
+//     path = "/etc/motd"
+//     content = "Managed by breezyvps\n"
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub struct CloudConfig {
+    #[serde(default)]
+    pub packages: Vec<String>,
+    #[serde(default)]
+    pub users: Vec<User>,
+    #[serde(default)]
+    pub files: Vec<WriteFile>,
+    // Run through sh, in order, once everything else is in place
+    #[serde(default)]
+    pub runcmd: Vec<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub struct User {
+    pub name: String,
+    // Passwordless sudo, as configure harden sets up
+    #[serde(default)]
+    pub sudo: bool,
+    // Public keys, or paths to local .pub files
+    #[serde(default)]
+    pub ssh_keys: Vec<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub struct WriteFile {
+    pub path: String,
+    pub content: Option<String>,
+    // Local file to take the content from instead
+    pub source: Option<String>,
+    pub permissions: Option<String>,
+    // e.g. deploy:deploy
+    pub owner: Option<String>,
+}
+
+// cloud-init's own field names, JSON being valid YAML
+#[derive(Serialize)]
+struct UserData<'a> {
+    #[serde(skip_serializing_if = "<[String]>::is_empty")]
+    packages: &'a [String],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    package_update: Option<bool>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    users: Vec<UserEntry<'a>>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    write_files: Vec<FileEntry<'a>>,
+    #[serde(skip_serializing_if = "<[String]>::is_empty")]
+    runcmd: &'a [String],
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum UserEntry<'a> {
+    // The image's own user, which listing users would otherwise drop
+    Default(&'static str),
+    User {
+        name: &'a str,
+        shell: &'static str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        sudo: Option<&'static str>,
+        ssh_authorized_keys: Vec<String>,
+    },
+}
+
+#[derive(Serialize)]
+struct FileEntry<'a> {
+    path: &'a str,
+    content: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    permissions: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    owner: Option<&'a str>,
+}
+
+fn read_local(path: &str) -> Result<String, String> {
+    let path = config::expand_home(path);
+    let mut contents = String::new();
+    File::open(&path)
+        .and_then(|mut f| f.read_to_string(&mut contents))
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    Ok(contents)
+}
+
+fn is_public_key(key: &str) -> bool {
+    ["ssh-", "ecdsa-", "sk-"].iter().any(|prefix| key.starts_with(prefix))
+}
+
+impl CloudConfig {
+    pub fn from_file(path: &str) -> Result<CloudConfig, String> {
+        CloudConfig::parse(&read_local(path)?)
+    }
+
+    pub fn parse(contents: &str) -> Result<CloudConfig, String> {
+        toml::from_str(contents).map_err(|e| format!("Invalid cloud-config file: {}", e))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == CloudConfig::default()
+    }
+
+    // The #cloud-config user-data, with key and source files read in
+    pub fn render(&self) -> Result<String, String> {
+        let mut users = Vec::new();
+        if !self.users.is_empty() {
+            users.push(UserEntry::Default("default"));
+        }
+        for user in self.users.iter() {
+            let mut keys = Vec::new();
+            for key in user.ssh_keys.iter() {
+                keys.push(if is_public_key(key) { key.clone() } else { read_local(key)?.trim().to_string() });
+            }
+            users.push(UserEntry::User {
+                name: &user.name,
+                shell: "/bin/bash",
+                sudo: if user.sudo { Some("ALL=(ALL) NOPASSWD:ALL") } else { None },
+                ssh_authorized_keys: keys,
+            });
+        }
+        let mut write_files = Vec::new();
+        for file in self.files.iter() {
+            let content = match (&file.content, &file.source) {
+                (Some(content), None) => content.clone(),
+                (None, Some(source)) => read_local(source)?,
+                _ => return Err(format!("{} needs exactly one of content or source", file.path)),
+            };
+            write_files.push(FileEntry {
+                path: &file.path,
+                content,
+                permissions: file.permissions.as_deref(),
+                owner: file.owner.as_deref(),
+            });
+        }
+        let user_data = UserData {
+            packages: &self.packages,
+            package_update: if self.packages.is_empty() { None } else { Some(true) },
+            users,
+            write_files,
+            runcmd: &self.runcmd,
+        };
+        let json = serde_json::to_string_pretty(&user_data).map_err(|e| e.to_string())?;
+        Ok(format!("#cloud-config\n{}\n", json))
+    }
+}
+
+// Writes generated user-data for doctl to read, readable only by us since it can carry keys and
+// secrets. Remove it once the droplet is created.
+pub fn write_user_data(name: &str, user_data: &str) -> Result<String, String> {
+    let path = format!("/tmp/{}-user-data", name);
+    // A leftover file, or a link someone else planted, would keep its own permissions
+    let _ = fs::remove_file(&path);
+    OpenOptions::new().write(true).create_new(true).mode(0o600).open(&path)
+        .and_then(|mut file| file.write_all(user_data.as_bytes()))
+        .map_err(|e| format!("Failed to write {}: {}", path, e))?;
+    Ok(path)
+}
+
+// Waits for cloud-init to finish on a freshly created host. Doesn't retry: call
+// readiness::wait_for_ssh first, which waits until logins work.
+pub fn wait_for_completion(remote: &Remote) -> Result<(), String> {
+    info!("Waiting for cloud-init on {}", remote.host);
+    let result = command::run_host_cmd(&remote.ssh_fresh(5, "cloud-init status --wait"));
+    match result.exit_code {
+        Some(0) => Ok(()),
+        // Finished, but with recoverable errors
+        Some(2) => {
+            println!("cloud-init finished with warnings, see /var/log/cloud-init-output.log on {}", remote.host);
+            Ok(())
+        },
+        _ => Err(format!("cloud-init failed on {}:\n\n{}{}", remote.host, result.stdout, result.stderr)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_cloud_config() {
+        let config = CloudConfig::parse(r#"
+packages = ["nginx"]
+runcmd = ["systemctl enable --now nginx"]
+
+[[users]]
+name = "deploy"
+sudo = true
+ssh_keys = ["ssh-ed25519 AAAAC3Nza me@laptop"]
+
+[[files]]
+path = "/etc/motd"
+content = "hi\n"
+permissions = "0644"
+"#).unwrap();
+        assert_eq!(config.render().unwrap(), r#"#cloud-config
+{
+  "packages": [
+    "nginx"
+  ],
+  "package_update": true,
+  "users": [
+    "default",
+    {
+      "name": "deploy",
+      "shell": "/bin/bash",
+      "sudo": "ALL=(ALL) NOPASSWD:ALL",
+      "ssh_authorized_keys": [
+        "ssh-ed25519 AAAAC3Nza me@laptop"
+      ]
+    }
+  ],
+  "write_files": [
+    {
+      "path": "/etc/motd",
+      "content": "hi\n",
+      "permissions": "0644"
+    }
+  ],
+  "runcmd": [
+    "systemctl enable --now nginx"
+  ]
+}
+"#);
+    }
+
+    #[test]
+    fn files_need_content_or_source() {
+        let config = CloudConfig { files: vec![WriteFile { path: "/etc/motd".to_string(), ..WriteFile::default() }], ..CloudConfig::default() };
+        assert!(config.render().is_err());
+        assert!(CloudConfig::default().is_empty());
+        assert_eq!(CloudConfig::default().render().unwrap(), "#cloud-config\n{}\n");
+    }
+}
@@ -1,4 +1,6 @@
-use std::process::Command;
+use std::io;
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
 
 #[derive(Clone)]
 pub struct Result {
@@ -8,26 +10,63 @@ pub struct Result {
     pub stderr: String
 }
 
-pub fn run_host_cmd(command_str: &str) -> Result {
-    let output = if cfg!(target_os = "windows") {
-        Command::new("cmd")
-                .args(&["/C", command_str])
-                .output()
-                .expect("failed to execute process")
+// Wraps s in single quotes so the shell sees it as one word
+pub fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+fn host_shell(command_str: &str) -> Command {
+    let mut command = if cfg!(target_os = "windows") {
+        let mut command = Command::new("cmd");
+        command.arg("/C");
+        command
     } else {
-        Command::new("sh")
-                .arg("-c")
-                .arg(command_str)
-                .output()
-                .expect("failed to execute process")
+        let mut command = Command::new("sh");
+        command.arg("-c");
+        command
     };
+    command.arg(command_str);
+    command
+}
+
+fn to_result(output: Output) -> Result {
     let stdout = String::from_utf8(output.stdout).expect("Failed to unpack valid utf-8 from stdout");
     let stderr = String::from_utf8(output.stderr).expect("Failed to unpack valid utf-8 from stderr");
-    let result = Result {
+    Result {
         exit_code: output.status.code(),
         success: output.status.success(),
-        stdout: stdout,
-        stderr: stderr
-    };
-    return result;
+        stdout,
+        stderr
+    }
+}
+
+pub fn run_host_cmd(command_str: &str) -> Result {
+    to_result(host_shell(command_str).output().expect("failed to execute process"))
+}
+
+// Same as run_host_cmd, feeding input to the command's stdin. Secrets go this way rather than
+// on a command line, where anyone on either host could read them from ps.
+pub fn run_host_cmd_with_input(command_str: &str, input: &str) -> Result {
+    let mut child = host_shell(command_str)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("failed to execute process");
+    if let Some(mut stdin) = child.stdin.take() {
+        // A command that exits without reading it all fails on its own
+        let _ = stdin.write_all(input.as_bytes());
+    }
+    to_result(child.wait_with_output().expect("failed to execute process"))
+}
+
+// Asks a yes/no question on the terminal, anything but y/yes counts as no
+pub fn confirm(prompt: &str) -> bool {
+    print!("{} [y/N] ", prompt);
+    let _ = io::stdout().flush();
+    let mut answer = String::new();
+    if io::stdin().read_line(&mut answer).is_err() {
+        return false;
+    }
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
 }
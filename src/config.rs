@@ -0,0 +1,207 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::PathBuf;
+use toml;
+
+// Built in fallbacks, used when neither the config file, environment nor command line say otherwise
+const DEFAULT_REGION: &str = "sfo1";
+const DEFAULT_SIZE: &str = "512mb";
+const DEFAULT_IMAGE: &str = "ubuntu-16-04-x64";
+const DEFAULT_USER: &str = "root";
+
+// Every field is optional so the same table works for the top level and for each profile
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub struct Defaults {
+    pub region: Option<String>,
+    pub size: Option<String>,
+    pub image: Option<String>,
+    pub domain: Option<String>,
+    pub ssh_keys: Option<Vec<String>>,
+    pub user: Option<String>,
+    pub token: Option<String>,
+}
+
+impl Defaults {
+    // Fields set on other take precedence over ours
+    fn overlay(&mut self, other: Defaults) {
+        if other.region.is_some() { self.region = other.region; }
+        if other.size.is_some() { self.size = other.size; }
+        if other.image.is_some() { self.image = other.image; }
+        if other.domain.is_some() { self.domain = other.domain; }
+        if other.ssh_keys.is_some() { self.ssh_keys = other.ssh_keys; }
+        if other.user.is_some() { self.user = other.user; }
+        if other.token.is_some() { self.token = other.token; }
+    }
+}
+
+// Layout of config.toml: top level defaults plus any number of [profiles.<name>] tables
+#[derive(Debug, Default, Deserialize)]
+pub struct ConfigFile {
+    #[serde(flatten)]
+    pub defaults: Defaults,
+    #[serde(default)]
+    pub profiles: HashMap<String, Defaults>,
+}
+
+// Fully resolved settings handed to the subcommands
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    pub region: String,
+    pub size: String,
+    pub image: String,
+    pub domain: Option<String>,
+    pub ssh_keys: Vec<String>,
+    pub user: String,
+    pub token: Option<String>,
+}
+
+// $BREEZYVPS_CONFIG, else $XDG_CONFIG_HOME/breezyvps/config.toml, else ~/.config/breezyvps/config.toml
+pub fn config_path() -> Option<PathBuf> {
+    if let Ok(path) = env::var("BREEZYVPS_CONFIG") {
+        return Some(PathBuf::from(path));
+    }
+    let base = match env::var("XDG_CONFIG_HOME") {
+        Ok(ref dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var("HOME").ok()?).join(".config"),
+    };
+    Some(base.join("breezyvps").join("config.toml"))
+}
+
+// Expands a leading ~/ to $HOME, leaving every other path untouched
+pub fn expand_home(path: &str) -> PathBuf {
+    if let Some(rest) = path.strip_prefix("~/") {
+        if let Ok(home) = env::var("HOME") {
+            return PathBuf::from(home).join(rest);
+        }
+    }
+    PathBuf::from(path)
+}
+
+pub fn parse(contents: &str) -> Result<ConfigFile, String> {
+    toml::from_str(contents).map_err(|e| format!("Invalid config file: {}", e))
+}
+
+fn from_env(getter: &dyn Fn(&str) -> Option<String>) -> Defaults {
+    let get = |key: &str| getter(key).filter(|v| !v.is_empty());
+    Defaults {
+        region: get("BREEZYVPS_REGION"),
+        size: get("BREEZYVPS_SIZE"),
+        image: get("BREEZYVPS_IMAGE"),
+        domain: get("BREEZYVPS_DOMAIN"),
+        ssh_keys: get("BREEZYVPS_SSH_KEYS")
+            .map(|keys| keys.split(',').map(|k| k.trim().to_string()).filter(|k| !k.is_empty()).collect()),
+        user: get("BREEZYVPS_USER"),
+        token: get("DIGITALOCEAN_ACCESS_TOKEN"),
+    }
+}
+
+// Precedence, lowest first: built in defaults, config file top level, selected profile, environment
+pub fn resolve(file: ConfigFile, profile: Option<&str>, getter: &dyn Fn(&str) -> Option<String>) -> Result<Config, String> {
+    let mut merged = file.defaults;
+    let profile = profile.map(|p| p.to_string()).or_else(|| getter("BREEZYVPS_PROFILE").filter(|p| !p.is_empty()));
+    if let Some(name) = profile {
+        match file.profiles.get(&name) {
+            Some(p) => merged.overlay(p.clone()),
+            None => {
+                let mut known: Vec<&String> = file.profiles.keys().collect();
+                known.sort();
+                return Err(format!("Unknown profile '{}', known profiles: {:?}", name, known));
+            }
+        }
+    }
+    merged.overlay(from_env(getter));
+
+    Ok(Config {
+        region: merged.region.unwrap_or_else(|| DEFAULT_REGION.to_string()),
+        size: merged.size.unwrap_or_else(|| DEFAULT_SIZE.to_string()),
+        image: merged.image.unwrap_or_else(|| DEFAULT_IMAGE.to_string()),
+        domain: merged.domain,
+        ssh_keys: merged.ssh_keys.unwrap_or_default(),
+        user: merged.user.unwrap_or_else(|| DEFAULT_USER.to_string()),
+        token: merged.token,
+    })
+}
+
+impl Config {
+    // Reads the config file (a missing file is fine) and resolves it against the process environment
+    pub fn load(profile: Option<&str>) -> Result<Config, String> {
+        let file = match config_path() {
+            Some(ref path) if path.exists() => {
+                let mut contents = String::new();
+                File::open(path)
+                    .and_then(|mut f| f.read_to_string(&mut contents))
+                    .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+                parse(&contents)?
+            },
+            _ => ConfigFile::default(),
+        };
+        resolve(file, profile, &|key| env::var(key).ok())
+    }
+
+    // doctl reads its token from the environment, so export ours for every child process
+    pub fn export_token(&self) {
+        if let Some(ref token) = self.token {
+            env::set_var("DIGITALOCEAN_ACCESS_TOKEN", token);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE: &str = r#"
+region = "nyc3"
+domain = "example.com"
+ssh_keys = ["laptop"]
+
+[profiles.work]
+domain = "work.example.com"
+user = "deploy"
+token = "secret"
+"#;
+
+    fn no_env(_: &str) -> Option<String> {
+        None
+    }
+
+    #[test]
+    fn missing_values_fall_back_to_builtins() {
+        let config = resolve(ConfigFile::default(), None, &no_env).unwrap();
+        assert_eq!(config.region, "sfo1");
+        assert_eq!(config.size, "512mb");
+        assert_eq!(config.user, "root");
+        assert_eq!(config.domain, None);
+    }
+
+    #[test]
+    fn profile_overrides_top_level() {
+        let config = resolve(parse(SAMPLE).unwrap(), Some("work"), &no_env).unwrap();
+        assert_eq!(config.region, "nyc3");
+        assert_eq!(config.domain, Some("work.example.com".to_string()));
+        assert_eq!(config.ssh_keys, vec!["laptop".to_string()]);
+        assert_eq!(config.user, "deploy");
+        assert_eq!(config.token, Some("secret".to_string()));
+    }
+
+    #[test]
+    fn environment_overrides_file() {
+        let env = |key: &str| match key {
+            "BREEZYVPS_PROFILE" => Some("work".to_string()),
+            "BREEZYVPS_DOMAIN" => Some("env.example.com".to_string()),
+            "BREEZYVPS_SSH_KEYS" => Some("a, b".to_string()),
+            _ => None,
+        };
+        let config = resolve(parse(SAMPLE).unwrap(), None, &env).unwrap();
+        assert_eq!(config.domain, Some("env.example.com".to_string()));
+        assert_eq!(config.ssh_keys, vec!["a".to_string(), "b".to_string()]);
+        assert_eq!(config.user, "deploy");
+    }
+
+    #[test]
+    fn unknown_profile_is_an_error() {
+        assert!(resolve(parse(SAMPLE).unwrap(), Some("home"), &no_env).is_err());
+    }
+}
@@ -1,19 +1,64 @@
 use std::fs::File;
 use std::io::prelude::*;
 use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
 use super::chain;
+use super::command;
+use super::docker;
+use super::firewall;
+use super::firewall::Backend;
+use super::packages;
+use super::packages::{Package, PackageManager};
+use super::runtimes;
+use super::runtimes::{NodeInstall, RustOptions};
+use super::remote::{OsFamily, Remote};
 
-pub fn install_nginx(host: &str) {
-    let nginx_install_command = format!("ssh root@{} 'apt-get update && apt-get install -y nginx'", host);
+// Installs packages, printing rather than returning any failure like the other installers
+fn install_packages(remote: &Remote, packages: &[Package]) -> bool {
+    match packages::install(remote, packages) {
+        Ok(()) => true,
+        Err(e) => {
+            println!("{}", e);
+            false
+        }
+    }
+}
+
+// Starts a service now and on every boot, with systemd or OpenRC
+fn enable_service(remote: &Remote, name: &str) {
     let _ = chain::CommandChain::new()
-        .cmd(&nginx_install_command)
+        .cmd(&remote.ssh_root(&packages::enable_service_script(name)))
         .execute();
 }
 
-fn create_host_file(host: &str, port: &str) -> String {
-    let filepath = format!("/tmp/{}.conf", host);
+pub fn install_nginx(remote: &Remote) {
+    if install_packages(remote, &[Package::Nginx]) {
+        enable_service(remote, "nginx");
+    }
+}
+
+// Alpine moved nginx's vhosts to http.d, everyone else reads conf.d
+pub fn nginx_conf_dir(family: OsFamily) -> &'static str {
+    match family {
+        OsFamily::Alpine => "/etc/nginx/http.d/",
+        _ => "/etc/nginx/conf.d/",
+    }
+}
+
+// Where nginx proxies to and deploy runs apps, unless told otherwise
+pub const DEFAULT_APP_PORT: &str = "8080";
+
+// Writes contents to /tmp/<filename> for copying to a remote, returns the local path
+pub fn write_local_file(filename: &str, contents: &str) -> String {
+    let filepath = format!("/tmp/{}", filename);
     let path = Path::new(&filepath);
-    let mut file = File::create(&path).expect("Failed to open file!");
+    let mut file = File::create(path).expect("Failed to open file!");
+    assert!(file.write_all(contents.as_bytes()).is_ok());
+    filepath
+}
+
+fn create_host_file(host: &str, port: &str) -> String {
 
     let template = r#"
 server {
@@ -40,82 +85,311 @@ server {
     // These are hacks LOL, cause format! didn't seem to work with multiline string
     let mut contents = template.replace("{1}", host);
     contents = contents.replace("{2}", port);
-    assert!(file.write_all(contents.as_bytes()).is_ok());
-    return filepath.clone();
+    write_local_file(&format!("{}.conf", host), &contents)
 }
 
-pub fn add_nginx_host(host: &str, port: &str) {
-    let filename = create_host_file(host, port);
-    let conf_create_command = format!("scp {} root@{}:/etc/nginx/conf.d/", filename, host);
+pub fn add_nginx_host(remote: &Remote, port: &str) {
+    let filename = create_host_file(&remote.host, port);
+    let conf_create_command = remote.scp_to_root(&filename, nginx_conf_dir(remote.os_family()), "644");
     let _ = chain::CommandChain::new()
         .cmd(&conf_create_command)
         .cmd(&format!("rm {}", filename))
         .execute();
 }
 
-pub fn install_letsencrypt_cert(host: &str) {
-    let install_certbot_cmd = format!("ssh root@{} 'add-apt-repository ppa:certbot/certbot && apt-get update && apt-get install -y python-certbot-nginx'", host);
-    let _ = chain::CommandChain::new()
-        .cmd(&install_certbot_cmd)
-        .execute();
-    let run_certbot_command = format!("ssh root@{} 'certbot --nginx -d {}'", host, host);
+pub fn install_letsencrypt_cert(remote: &Remote) {
+    if !install_packages(remote, &[Package::CertbotNginx]) {
+        return;
+    }
+    let run_certbot_command = remote.ssh_root(&format!("certbot --nginx -d {}", remote.host));
     println!("Please run:\n\t{}", run_certbot_command);
 }
 
-pub fn install_rust(host: &str) {
-    let install_rust_cmd = format!("ssh root@{} 'curl https://sh.rustup.rs -sSf | sh -s -- -y'", host);
-    let _ = chain::CommandChain::new()
-        .cmd(&install_rust_cmd)
-        .execute();
+// Runs script as the ssh user, then checks the version command reports wanted when it's a
+// plain number
+fn run_and_verify(remote: &Remote, what: &str, script: &str, version_cmd: &str, wanted: Option<&str>) -> bool {
+    let result = remote.run(script);
+    if !result.success {
+        println!("Failed to install {} on {}:\n\n{}", what, remote.host, result.stderr);
+        return false;
+    }
+    verify_version(remote, what, version_cmd, wanted)
 }
 
-pub fn install_python(host: &str) {
-    let install_python_cmd = format!("ssh root@{} 'apt-get update && apt-get install -y python'", host);
-    let _ = chain::CommandChain::new()
-        .cmd(&install_python_cmd)
-        .execute();
+fn verify_version(remote: &Remote, what: &str, version_cmd: &str, wanted: Option<&str>) -> bool {
+    let result = remote.run(version_cmd);
+    let reported = result.stdout.trim().to_string() + result.stderr.trim();
+    if !result.success {
+        println!("{} isn't working on {}: {}", what, remote.host, reported);
+        return false;
+    }
+    match wanted {
+        Some(wanted) if runtimes::is_numeric_version(wanted) && !runtimes::version_matches(&reported, wanted) => {
+            println!("Wanted {} {} on {}, but {} is active", what, wanted, remote.host, reported);
+            false
+        },
+        _ => {
+            println!("{} on {}: {}", what, remote.host, reported);
+            true
+        },
+    }
 }
 
-pub fn install_jekyll(host: &str) {
-    let _ = chain::CommandChain::new()
-        .cmd(&format!("ssh root@{} 'apt-get update && apt-get install -y rubygems build-essential ruby-dev'", host))
-        .cmd(&format!("ssh root@{} 'gem install jekyll bundler'", host))
-        .execute();
+// rustup and a toolchain for options.user (default the ssh user), skipped when it's already
+// there with everything asked for
+pub fn install_rust(remote: &Remote, options: &RustOptions) {
+    if let Err(e) = options.validate() {
+        println!("{}", e);
+        return;
+    }
+    let user = options.user.unwrap_or(&remote.user);
+    let as_user = |cmd: &str| remote.as_user(user, cmd);
+    let rustc_version = as_user(&format!("{} && rustc --version", runtimes::CARGO_ENV));
+    if remote.run(&as_user(&options.installed_check())).success {
+        println!("{} is already installed for {}, skipping", options.toolchain(), user);
+        verify_version(remote, "rust", &rustc_version, options.toolchain);
+        return;
+    }
+    // rustc links with the system C toolchain
+    if !install_packages(remote, &[Package::Curl, Package::BuildTools]) {
+        return;
+    }
+    run_and_verify(remote, "rust", &as_user(&options.install_script()), &rustc_version, options.toolchain);
 }
 
-pub fn renew_cert(host: &str) {
-    let _ = chain::CommandChain::new()
-        .cmd(&format!("ssh root@{} 'certbot --nginx renew'", host))
-        .execute();
+// python3 with venv and pip, version from deadsnakes or the distro when given, and optionally
+// a virtualenv at venv for the ssh user
+pub fn install_python(remote: &Remote, version: Option<&str>, venv: Option<&str>) {
+    let command = match PackageManager::detect(remote).and_then(|manager| runtimes::python_command(manager, version)) {
+        Ok(command) => command,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+    match command {
+        Some(command) => {
+            let result = remote.run_root(&command);
+            if !result.success {
+                println!("Failed to install python{} on {}:\n\n{}", version.unwrap_or(""), remote.host, result.stderr);
+                return;
+            }
+        },
+        None => {
+            if !install_packages(remote, &[Package::Python3]) {
+                return;
+            }
+        },
+    }
+    let python = runtimes::python_binary(version);
+    if !verify_version(remote, "python", &format!("{} --version", python), version) {
+        return;
+    }
+    if let Some(venv) = venv {
+        let result = remote.run(&runtimes::venv_script(&python, venv));
+        if result.success {
+            println!("Created virtualenv {} with {}", venv, python);
+        } else {
+            println!("Failed to create virtualenv {}:\n\n{}", venv, result.stderr);
+        }
+    }
 }
 
-pub fn setup_iptables(host: &str) {
-    let _ = chain::CommandChain::new()
-        .cmd(&format!("ssh root@{} 'iptables -P INPUT ACCEPT'", host)) // First, switch input back to accept
-        .cmd(&format!("ssh root@{} 'iptables -F'", host))
-        .cmd(&format!("ssh root@{} 'iptables -A INPUT -p tcp --tcp-flags ALL NONE -j DROP'", host))
-        .cmd(&format!("ssh root@{} 'iptables -A INPUT -p tcp ! --syn -m state --state NEW -j DROP'", host))
-        .cmd(&format!("ssh root@{} 'iptables -A INPUT -p tcp --tcp-flags ALL ALL -j DROP'", host))
-        .cmd(&format!("ssh root@{} 'iptables -A INPUT -s 127.0.0.1 -j ACCEPT'", host))
-        .cmd(&format!("ssh root@{} 'iptables -A INPUT -p tcp -m tcp --dport 80 -j ACCEPT'", host))
-        .cmd(&format!("ssh root@{} 'iptables -A INPUT -p tcp -m tcp --dport 443 -j ACCEPT'", host))
-        .cmd(&format!("ssh root@{} 'iptables -A INPUT -p tcp -m tcp --dport 22 -j ACCEPT'", host))
-        .cmd(&format!("ssh root@{} 'iptables -A INPUT -m state --state ESTABLISHED,RELATED -j ACCEPT'", host))
-        .cmd(&format!("ssh root@{} 'iptables -P OUTPUT ACCEPT'", host))
-        .cmd(&format!("ssh root@{} 'iptables -P INPUT DROP'", host))
-        .execute();
+// Jekyll and bundler, on the distro's ruby or on ruby_version installed with rbenv for the ssh user
+pub fn install_jekyll(remote: &Remote, ruby_version: Option<&str>) {
+    match ruby_version {
+        None => {
+            if !install_packages(remote, &[Package::RubyDev, Package::BuildTools]) {
+                return;
+            }
+            let _ = chain::CommandChain::new()
+                .cmd(&remote.ssh_root("gem install jekyll bundler"))
+                .execute();
+        },
+        Some(version) => {
+            if let Err(e) = runtimes::validate_version(version) {
+                println!("{}", e);
+                return;
+            }
+            if !install_packages(remote, &[Package::RubyBuildDeps, Package::BuildTools]) {
+                return;
+            }
+            let ruby = format!("{} && ruby --version", runtimes::RBENV_ENV);
+            if !run_and_verify(remote, "ruby", &runtimes::rbenv_script(version), &ruby, Some(version)) {
+                return;
+            }
+            let _ = chain::CommandChain::new()
+                .cmd(&remote.ssh(&format!("{} && gem install jekyll bundler", runtimes::RBENV_ENV)))
+                .execute();
+        },
+    }
 }
 
-pub fn install_sqlite3(host: &str) {
+pub fn renew_cert(remote: &Remote) {
     let _ = chain::CommandChain::new()
-        .cmd(&format!("ssh root@{} 'apt-get update'", host))
-        .cmd(&format!("ssh root@{} 'apt-get install -y sqlite3 libsqlite3-dev'", host))
+        .cmd(&remote.ssh_root("certbot --nginx renew"))
         .execute();
 }
 
-pub fn install_nodejs(host: &str) {
-    let _ = chain::CommandChain::new()
-        .cmd(&format!("ssh root@{} 'apt-get update'", host))
-        .cmd(&format!("ssh root@{} 'apt-get install -y nodejs npm'", host))
+// Prefer nftables where the distro ships it, iptables everywhere else
+fn detect_firewall_backend(remote: &Remote) -> Backend {
+    if remote.run_root("command -v nft").success {
+        Backend::Nftables
+    } else {
+        Backend::Iptables
+    }
+}
+
+// Uploads the staged rules and applies them with the revert timer armed, then proves we can
+// still get in over a brand new ssh connection, which disarms the timer. Returns whether the
+// new rules were confirmed.
+fn guarded_apply(remote: &Remote, policy: &firewall::Policy, backend: Backend, revert_after: u32) -> bool {
+    let mut chain = chain::CommandChain::new()
+        .cmd(&remote.ssh_root(&Backend::prepare_command()));
+    let mut local_files = Vec::new();
+    for (remote_path, contents) in policy.staged_rules(backend) {
+        let filename = remote_path.rsplit('/').next().unwrap_or(remote_path);
+        local_files.push((write_local_file(&format!("{}-{}", remote.host, filename), &contents), remote_path));
+    }
+    for &(ref local_path, remote_path) in local_files.iter() {
+        chain = chain
+            .cmd(&remote.scp_to_root(local_path, remote_path, "600"))
+            .cmd(&format!("rm {}", local_path));
+    }
+    let res = chain
+        .cmd(&remote.ssh_root(&backend.guarded_apply_script(revert_after)))
         .execute();
+    if !res.result.is_some_and(|r| r.success) {
+        println!("Failed to apply firewall rules on {}, the previous rules are back in place", remote.host);
+        return false;
+    }
+
+    let confirm_cmd = remote.ssh_fresh(5, &remote.sudo(&Backend::confirm_command()));
+    let deadline = Instant::now() + Duration::from_secs(u64::from(revert_after.saturating_sub(10).max(1)));
+    loop {
+        let result = command::run_host_cmd(&confirm_cmd);
+        if result.success {
+            info!("Reconnected to {}, keeping the new firewall rules", remote.host);
+            return true;
+        }
+        if Instant::now() >= deadline {
+            break;
+        }
+        thread::sleep(Duration::from_secs(2));
+    }
+    println!("Couldn't reconnect to {}, the previous firewall rules will be restored within {} seconds", remote.host, revert_after);
+    false
+}
+
+fn persist_firewall(remote: &Remote, backend: Backend, family: OsFamily) {
+    let commands: Vec<String> = match backend {
+        Backend::Iptables => firewall::iptables_persist_commands(family).iter().map(|c| c.to_string()).collect(),
+        Backend::Nftables => firewall::nftables_persist_commands(family, firewall::STAGED_NFT),
+    };
+    if commands.is_empty() {
+        warn!("Don't know how to persist firewall rules on {}, they'll be lost on reboot", remote.host);
+    }
+    let mut chain = chain::CommandChain::new();
+    for command in commands.iter() {
+        chain = chain.cmd(&remote.ssh_root(command));
+    }
+    let _ = chain.execute();
+}
+
+// Applies policy with backend (detected when not given) for IPv4 and IPv6, and once a new ssh
+// connection confirms we aren't locked out, saves it so it survives reboots. Otherwise the host
+// reverts to its previous rules after revert_after seconds. Returns whether the rules stuck.
+pub fn setup_iptables(remote: &Remote, policy: &firewall::Policy, backend: Option<Backend>, revert_after: u32) -> bool {
+    if !policy.allows_ssh() {
+        warn!("Policy doesn't allow ssh on port {}, this will lock us out of {}", policy.ssh_port, remote.host);
+    }
+    let family = remote.os_family();
+    let backend = backend.unwrap_or_else(|| detect_firewall_backend(remote));
+    info!("Applying firewall policy to {} with {:?}", remote.host, backend);
+    if backend == Backend::Nftables {
+        // nft has to be there before anything is applied
+        let mut chain = chain::CommandChain::new();
+        for command in firewall::nftables_install_commands(family).iter() {
+            chain = chain.cmd(&remote.ssh_root(command));
+        }
+        let _ = chain.execute();
+    }
+    if !guarded_apply(remote, policy, backend, revert_after) {
+        return false;
+    }
+    persist_firewall(remote, backend, family);
+    true
+}
+
+// Whether the host drops inbound traffic by default, i.e. already has a firewall up
+pub fn firewall_active(remote: &Remote) -> bool {
+    remote.run_root("iptables -S INPUT 2>/dev/null | grep -q -- '-P INPUT DROP' || nft list ruleset 2>/dev/null | grep -q 'policy drop'").success
+}
+
+// Docker Engine and the compose plugin, usable by the ssh user from their next login
+pub fn install_docker(remote: &Remote) {
+    let manager = match PackageManager::detect(remote) {
+        Ok(manager) => manager,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+    let result = remote.run_root(&docker::install_command(manager));
+    if !result.success {
+        println!("Failed to install docker on {}:\n\n{}", remote.host, result.stderr);
+        return;
+    }
+    enable_service(remote, "docker");
+    if remote.user != "root" {
+        let _ = remote.run_root(&docker::docker_group_command(&remote.user));
+    }
+    verify_version(remote, "docker compose", &remote.sudo("docker compose version"), None);
+}
+
+pub fn install_sqlite3(remote: &Remote) {
+    install_packages(remote, &[Package::Sqlite3]);
+}
+
+// The distro's node, a release line from NodeSource, or an exact version through nvm
+pub fn install_nodejs(remote: &Remote, version: Option<&str>, nvm: bool) {
+    let install = match runtimes::node_install(version, nvm) {
+        Ok(install) => install,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+    match install {
+        NodeInstall::System => {
+            if install_packages(remote, &[Package::Nodejs]) {
+                verify_version(remote, "node", "node --version", None);
+            }
+        },
+        NodeInstall::NodeSource(line) => {
+            let command = match PackageManager::detect(remote).and_then(|manager| runtimes::nodesource_command(manager, &line)) {
+                Ok(command) => command,
+                Err(e) => {
+                    println!("{}", e);
+                    return;
+                }
+            };
+            if !install_packages(remote, &[Package::Curl]) {
+                return;
+            }
+            let result = remote.run_root(&command);
+            if !result.success {
+                println!("Failed to install node {} on {}:\n\n{}", line, remote.host, result.stderr);
+                return;
+            }
+            verify_version(remote, "node", "node --version", Some(&line));
+        },
+        NodeInstall::Nvm(version) => {
+            if !install_packages(remote, &[Package::Curl]) {
+                return;
+            }
+            run_and_verify(remote, "node", &runtimes::nvm_script(&version),
+                           &format!("{} && node --version", runtimes::NVM_ENV), Some(&version));
+        },
+    }
 }
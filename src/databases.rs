@@ -0,0 +1,226 @@
+use std::fs::File;
+use std::io::prelude::*;
+use super::packages;
+use super::packages::{Package, PackageManager};
+use super::remote::Remote;
+use super::service;
+
+// A data store role, always bound to localhost so setup_iptables never has to open its port
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Engine {
+    Postgres,
+    Mysql,
+    Redis,
+}
+
+// Postgres and MySQL identifiers we're happy to put in SQL unquoted
+pub fn validate_identifier(name: &str) -> Result<(), String> {
+    let mut chars = name.chars();
+    let valid = match chars.next() {
+        Some(c) => (c.is_ascii_lowercase() || c == '_')
+            && chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+            && name.len() <= 63,
+        None => false,
+    };
+    if valid { Ok(()) } else { Err(format!("Invalid database or user name: {}", name)) }
+}
+
+// 24 random bytes as hex, safe in URLs, SQL strings and config files alike
+pub fn generate_password() -> Result<String, String> {
+    let mut bytes = [0u8; 24];
+    File::open("/dev/urandom")
+        .and_then(|mut f| f.read_exact(&mut bytes))
+        .map_err(|e| format!("Failed to read /dev/urandom: {}", e))?;
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+impl Engine {
+    pub fn package(&self) -> Package {
+        match *self {
+            Engine::Postgres => Package::PostgresServer,
+            Engine::Mysql => Package::MysqlServer,
+            Engine::Redis => Package::RedisServer,
+        }
+    }
+
+    pub fn service_name(&self, manager: PackageManager) -> &'static str {
+        match (*self, manager) {
+            (Engine::Postgres, _) => "postgresql",
+            (Engine::Mysql, _) => "mariadb",
+            (Engine::Redis, PackageManager::Apt) => "redis-server",
+            (Engine::Redis, _) => "redis",
+        }
+    }
+
+    // Root commands creating the initial data directory where the package doesn't
+    pub fn init_command(&self, manager: PackageManager) -> Option<&'static str> {
+        match (*self, manager) {
+            (Engine::Postgres, PackageManager::Dnf) => Some("[ -f /var/lib/pgsql/data/PG_VERSION ] || postgresql-setup --initdb"),
+            (Engine::Postgres, PackageManager::Apk) => Some("ls /var/lib/postgresql/*/data/PG_VERSION >/dev/null 2>&1 || /etc/init.d/postgresql setup"),
+            (Engine::Mysql, PackageManager::Apk) => Some("[ -d /var/lib/mysql/mysql ] || /etc/init.d/mariadb setup"),
+            _ => None,
+        }
+    }
+
+    // The env var apps conventionally read the connection string from
+    pub fn env_var(&self) -> &'static str {
+        match *self {
+            Engine::Redis => "REDIS_URL",
+            _ => "DATABASE_URL",
+        }
+    }
+
+    pub fn connection_string(&self, database: &str, user: &str, password: &str) -> String {
+        match *self {
+            Engine::Postgres => format!("postgres://{}:{}@localhost:5432/{}", user, password, database),
+            Engine::Mysql => format!("mysql://{}:{}@localhost:3306/{}", user, password, database),
+            Engine::Redis => format!("redis://:{}@localhost:6379/0", password),
+        }
+    }
+
+    // Root commands pinning the server to localhost and creating the database and user, or
+    // setting the password for Redis. The SQL or Redis config carrying the password comes in on
+    // stdin, see provision_input, and only ever goes through shell builtins. Safe to rerun, which
+    // rotates the password.
+    pub fn provision_script(&self, manager: PackageManager, database: &str, user: &str) -> String {
+        let service = self.service_name(manager);
+        match *self {
+            Engine::Postgres => {
+                // Fedora's initdb only trusts ident for TCP, ahead of anything appended, so the
+                // password login goes first. md5 also accepts scram passwords.
+                let hba = format!("hba=$(su postgres -s /bin/sh -c \"psql -tAc 'SHOW hba_file'\") && \
+                                   for addr in 127.0.0.1/32 ::1/128; do line=\"host {} {} $addr md5\"; \
+                                   grep -qxF \"$line\" \"$hba\" || sed -i \"1i $line\" \"$hba\" || exit 1; done", database, user);
+                format!("sql=$(cat) && {} && cd /tmp && printf '%s\\n' \"$sql\" | su postgres -s /bin/sh -c 'psql -v ON_ERROR_STOP=1' && {} && {}",
+                        packages::enable_service_script(service), hba, packages::restart_service_script(service))
+            },
+            Engine::Mysql => {
+                let conf = match manager {
+                    PackageManager::Apt => "/etc/mysql/mariadb.conf.d/60-breezyvps.cnf",
+                    _ => "/etc/my.cnf.d/breezyvps.cnf",
+                };
+                format!("sql=$(cat) && mkdir -p $(dirname {conf}) && printf '[mysqld]\\nbind-address = 127.0.0.1\\n' > {conf} && {restart} && \
+                         printf '%s\\n' \"$sql\" | mysql",
+                        conf = conf, restart = packages::restart_service_script(service))
+            },
+            Engine::Redis => {
+                format!("block=$(cat) && conf=$(ls /etc/redis/redis.conf /etc/redis.conf 2>/dev/null | head -n 1) && [ -n \"$conf\" ] && \
+                         sed -i '/^# BEGIN breezyvps$/,/^# END breezyvps$/d' \"$conf\" && printf '%s\\n' \"$block\" >> \"$conf\" && {}",
+                        packages::restart_service_script(service))
+            },
+        }
+    }
+
+    // What provision_script reads from stdin
+    pub fn provision_input(&self, database: &str, user: &str, password: &str) -> String {
+        match *self {
+            Engine::Postgres => format!("ALTER SYSTEM SET listen_addresses = 'localhost';
+DO $$BEGIN IF NOT EXISTS (SELECT FROM pg_roles WHERE rolname = '{user}') THEN CREATE ROLE {user} LOGIN; END IF; END$$;
+ALTER ROLE {user} WITH LOGIN PASSWORD '{password}';
+SELECT 'CREATE DATABASE {database} OWNER {user}' WHERE NOT EXISTS (SELECT FROM pg_database WHERE datname = '{database}')\\gexec
+", user = user, password = password, database = database),
+            Engine::Mysql => format!("CREATE DATABASE IF NOT EXISTS {database};
+CREATE USER IF NOT EXISTS '{user}'@'localhost' IDENTIFIED BY '{password}';
+ALTER USER '{user}'@'localhost' IDENTIFIED BY '{password}';
+GRANT ALL PRIVILEGES ON {database}.* TO '{user}'@'localhost';
+FLUSH PRIVILEGES;
+", user = user, password = password, database = database),
+            Engine::Redis => format!("# BEGIN breezyvps\nbind 127.0.0.1\nprotected-mode yes\nrequirepass {}\n# END breezyvps\n", password),
+        }
+    }
+}
+
+pub struct DatabaseOptions<'a> {
+    // Ignored for Redis
+    pub database: &'a str,
+    pub user: &'a str,
+    // Also store the connection string in this service's env file on the host
+    pub app: Option<&'a str>,
+}
+
+// Installs the server, provisions it and prints the connection string
+pub fn provision(remote: &Remote, engine: Engine, options: &DatabaseOptions) -> Result<String, String> {
+    if engine != Engine::Redis {
+        validate_identifier(options.database)?;
+        validate_identifier(options.user)?;
+    }
+    let manager = PackageManager::detect(remote)?;
+    packages::install(remote, &[engine.package()])?;
+    if let Some(init) = engine.init_command(manager) {
+        let result = remote.run_root(init);
+        if !result.success {
+            return Err(format!("Failed to initialize {:?}:\n\n{}", engine, result.stderr));
+        }
+    }
+    let password = generate_password()?;
+    let result = remote.run_root_with_input(&engine.provision_script(manager, options.database, options.user),
+                                            &engine.provision_input(options.database, options.user, &password));
+    if !result.success {
+        return Err(format!("Failed to set up {:?}:\n\n{}", engine, result.stderr));
+    }
+
+    let url = engine.connection_string(options.database, options.user, &password);
+    if let Some(app) = options.app {
+        let line = format!("{}={}", engine.env_var(), url);
+        let result = remote.run_root_with_input(&service::set_env_var_script(app, engine.env_var()), &line);
+        if !result.success {
+            return Err(format!("Failed to store {} for {}:\n\n{}", engine.env_var(), app, result.stderr));
+        }
+        println!("Stored {} in {}", engine.env_var(), service::env_file_path(app));
+    }
+    println!("{}={}", engine.env_var(), url);
+    Ok(url)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validates_identifiers() {
+        assert!(validate_identifier("app").is_ok());
+        assert!(validate_identifier("app_2").is_ok());
+        assert!(validate_identifier("App").is_err());
+        assert!(validate_identifier("app'; DROP").is_err());
+        assert!(validate_identifier("").is_err());
+    }
+
+    #[test]
+    fn generates_distinct_hex_passwords() {
+        let (a, b) = (generate_password().unwrap(), generate_password().unwrap());
+        assert_eq!(a.len(), 48);
+        assert!(a.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn connection_strings() {
+        assert_eq!(Engine::Postgres.connection_string("app", "web", "pw"), "postgres://web:pw@localhost:5432/app");
+        assert_eq!(Engine::Mysql.connection_string("app", "web", "pw"), "mysql://web:pw@localhost:3306/app");
+        assert_eq!(Engine::Redis.connection_string("app", "web", "pw"), "redis://:pw@localhost:6379/0");
+    }
+
+    #[test]
+    fn starts_postgres_before_psql_and_allows_password_logins() {
+        let script = Engine::Postgres.provision_script(PackageManager::Dnf, "app", "web");
+        assert!(script.starts_with("sql=$(cat) && (systemctl enable --now postgresql || (rc-update add postgresql default && rc-service postgresql start)) && cd /tmp"));
+        assert!(script.contains("line=\"host app web $addr md5\""));
+        assert!(script.ends_with("systemctl restart postgresql || (rc-update add postgresql default && rc-service postgresql restart))"));
+    }
+
+    #[test]
+    fn keeps_passwords_off_the_command_line() {
+        for engine in [Engine::Postgres, Engine::Mysql, Engine::Redis].iter() {
+            assert!(!engine.provision_script(PackageManager::Apt, "app", "web").contains("pw"));
+            assert!(engine.provision_input("app", "web", "s3cret").contains("s3cret"));
+        }
+    }
+
+    #[test]
+    fn binds_to_localhost() {
+        assert!(Engine::Postgres.provision_input("app", "web", "pw").starts_with("ALTER SYSTEM SET listen_addresses = 'localhost';"));
+        assert!(Engine::Mysql.provision_script(PackageManager::Dnf, "app", "web").contains("bind-address = 127.0.0.1"));
+        assert!(Engine::Redis.provision_input("app", "web", "pw").contains("bind 127.0.0.1\n"));
+        assert!(Engine::Redis.provision_script(PackageManager::Apt, "app", "web").ends_with("systemctl restart redis-server || (rc-update add redis-server default && rc-service redis-server restart))"));
+    }
+}
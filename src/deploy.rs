@@ -0,0 +1,333 @@
+use std::fs;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+use toml;
+use super::chain;
+use super::command;
+use super::command::shell_quote;
+use super::configure;
+use super::docker;
+use super::remote::Remote;
+use super::service;
+use super::service::ServiceUnit;
+
+// Left out of directory syncs, they're rebuilt on the host anyway. .env is the host's own,
+// uploaded on its own by deploy compose --env-file.
+const SYNC_EXCLUDES: &[&str] = &[".git", "target", "node_modules", "_site", ".jekyll-cache", ".env"];
+
+// Where the code to deploy comes from
+#[derive(Clone, Debug, PartialEq)]
+pub enum Source {
+    // A local directory, as it is on disk
+    Dir(String),
+    // A commit, branch or tag from the git repository at repo
+    GitRef { repo: String, reference: String },
+}
+
+// How a project gets built and run, detected from the files at its root
+#[derive(Clone, Debug, PartialEq)]
+pub enum Build {
+    // Cargo project with the named binary
+    Cargo(String),
+    Npm,
+    Jekyll,
+    Unknown,
+}
+
+impl Build {
+    pub fn build_command(&self) -> Option<String> {
+        match *self {
+            Build::Cargo(_) => Some(". \"$HOME/.cargo/env\" 2>/dev/null; cargo build --release".to_string()),
+            Build::Npm => Some("npm install && npm run build --if-present".to_string()),
+            Build::Jekyll => Some("bundle install && bundle exec jekyll build".to_string()),
+            Build::Unknown => None,
+        }
+    }
+
+    // Runs in the app directory with PORT set to the port nginx proxies to
+    pub fn start_command(&self) -> Option<String> {
+        match *self {
+            Build::Cargo(ref binary) => Some(format!("exec target/release/{}", binary)),
+            Build::Npm => Some("exec npm start".to_string()),
+            Build::Jekyll => Some("exec bundle exec jekyll serve --skip-initial-build --no-watch --host 127.0.0.1 --port $PORT".to_string()),
+            Build::Unknown => None,
+        }
+    }
+}
+
+// files are the names at the project root, cargo_toml the contents of its Cargo.toml if any
+pub fn detect_build(files: &[String], cargo_toml: Option<&str>) -> Build {
+    let has = |name: &str| files.iter().any(|f| f == name);
+    if has("Cargo.toml") {
+        let package = cargo_toml
+            .and_then(|contents| contents.parse::<toml::Value>().ok())
+            .and_then(|manifest| manifest.get("package")
+                .and_then(|p| p.get("name"))
+                .and_then(|n| n.as_str())
+                .map(|n| n.to_string()));
+        if let Some(name) = package {
+            return Build::Cargo(name);
+        }
+    }
+    if has("package.json") {
+        return Build::Npm;
+    }
+    if has("_config.yml") {
+        return Build::Jekyll;
+    }
+    Build::Unknown
+}
+
+fn git_output(repo: &str, args: &str) -> Result<String, String> {
+    let result = command::run_host_cmd(&format!("git -C {} {}", shell_quote(repo), args));
+    if !result.success {
+        return Err(format!("git {} failed: {}", args, result.stderr.trim()));
+    }
+    Ok(result.stdout)
+}
+
+// Root file names and Cargo.toml contents of the source, for detect_build
+fn source_files(source: &Source) -> Result<(Vec<String>, Option<String>), String> {
+    match *source {
+        Source::Dir(ref dir) => {
+            let entries = fs::read_dir(dir).map_err(|e| format!("Failed to read {}: {}", dir, e))?;
+            let files = entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.file_name().to_string_lossy().into_owned())
+                .collect();
+            let mut cargo_toml = String::new();
+            let cargo_toml = File::open(Path::new(dir).join("Cargo.toml"))
+                .and_then(|mut f| f.read_to_string(&mut cargo_toml))
+                .ok()
+                .map(|_| cargo_toml);
+            Ok((files, cargo_toml))
+        },
+        Source::GitRef { ref repo, ref reference } => {
+            let files: Vec<String> = git_output(repo, &format!("ls-tree --name-only {}", shell_quote(reference)))?
+                .lines()
+                .map(|l| l.to_string())
+                .collect();
+            let cargo_toml = git_output(repo, &format!("show {}", shell_quote(&format!("{}:Cargo.toml", reference)))).ok();
+            Ok((files, cargo_toml))
+        },
+    }
+}
+
+// Host command copying source into dir on the remote
+pub fn sync_command(remote: &Remote, source: &Source, dir: &str) -> String {
+    match *source {
+        Source::Dir(ref local) => {
+            let excludes: Vec<String> = SYNC_EXCLUDES.iter().map(|e| format!("--exclude {}", e)).collect();
+            format!("rsync -az --delete {} -e {} {}/ {}:{}/", excludes.join(" "), shell_quote(&remote.ssh_transport()),
+                    shell_quote(local.trim_end_matches('/')), remote.target(), dir)
+        },
+        Source::GitRef { ref repo, ref reference } => {
+            format!("git -C {} archive --format=tar {} | {}",
+                    shell_quote(repo), shell_quote(reference), remote.ssh(&extract_script(dir)))
+        },
+    }
+}
+
+// Remote commands unpacking a tar from stdin as dir's new contents, so files deleted from the
+// repository go from dir too. Unpacks next to the old contents first, leaving them alone if the
+// archive is broken, and like rsync neither copies nor deletes SYNC_EXCLUDES.
+fn extract_script(dir: &str) -> String {
+    let dir = shell_quote(dir);
+    let keep: Vec<String> = SYNC_EXCLUDES.iter().map(|e| format!("! -name {}", e)).collect();
+    let skip: Vec<String> = SYNC_EXCLUDES.iter().map(|e| format!("\"$new\"/{}", e)).collect();
+    format!("new=$(mktemp -d {0}/.breezyvps-sync.XXXXXX) && {{ tar -x -C \"$new\" || {{ rm -rf \"$new\"; false; }}; }} && \
+             rm -rf {2} && find {0} -mindepth 1 -maxdepth 1 ! -path \"$new\" {1} -exec rm -rf {{}} + && \
+             find \"$new\" -mindepth 1 -maxdepth 1 -exec mv {{}} {0}/ \\; && rmdir \"$new\"", dir, keep.join(" "), skip.join(" "))
+}
+
+// The port an nginx vhost written by add_nginx_host proxies to
+pub fn parse_proxy_port(conf: &str) -> Option<String> {
+    conf.lines()
+        .filter_map(|line| line.trim().strip_prefix("proxy_pass http://localhost:"))
+        .map(|rest| rest.trim_end_matches(';').trim_end_matches('/').to_string())
+        .find(|port| !port.is_empty() && port.chars().all(|c| c.is_ascii_digit()))
+}
+
+fn vhost_port(remote: &Remote) -> Option<String> {
+    let result = remote.run(&format!("cat /etc/nginx/conf.d/{0}.conf 2>/dev/null || cat /etc/nginx/http.d/{0}.conf", remote.host));
+    if !result.success {
+        return None;
+    }
+    parse_proxy_port(&result.stdout)
+}
+
+pub struct DeployOptions<'a> {
+    pub name: &'a str,
+    pub source: Source,
+    // Default: /srv/<name>
+    pub dir: Option<&'a str>,
+    // Default: the port the host's nginx vhost proxies to, else configure::DEFAULT_APP_PORT
+    pub port: Option<&'a str>,
+    // Default: detected from the project, unless skip_build
+    pub build: Option<&'a str>,
+    pub skip_build: bool,
+    // Default: detected
+    pub start: Option<&'a str>,
+}
+
+// Syncs the source to the host, builds it there and (re)starts it as a systemd service
+// (see service::ServiceUnit) listening on the port nginx proxies to
+pub fn deploy(remote: &Remote, options: &DeployOptions) -> Result<(), String> {
+    let (files, cargo_toml) = source_files(&options.source)?;
+    let detected = detect_build(&files, cargo_toml.as_deref());
+    let build = match options.build {
+        Some(build) => Some(build.to_string()),
+        None if options.skip_build => None,
+        None => detected.build_command(),
+    };
+    let start = options.start.map(|s| s.to_string())
+        .or_else(|| detected.start_command())
+        .ok_or_else(|| "Couldn't tell how to run this project, pass --start".to_string())?;
+    let dir = options.dir.map(|d| d.to_string())
+        .unwrap_or_else(|| format!("/srv/{}", options.name));
+    let port = options.port.map(|p| p.to_string())
+        .or_else(|| vhost_port(remote))
+        .unwrap_or_else(|| configure::DEFAULT_APP_PORT.to_string());
+    info!("Deploying {} to {}:{} ({:?}), serving on port {}", options.name, remote.host, dir, detected, port);
+
+    let res = chain::CommandChain::new()
+        .cmd(&remote.ssh_root(&format!("mkdir -p {0} && chown {1} {0}", shell_quote(&dir), remote.user)))
+        .cmd(&sync_command(remote, &options.source, &dir))
+        .execute();
+    if let Some(ref result) = res.result {
+        if !result.success {
+            return Err(format!("Failed to copy {} to {}:\n\n{}", options.name, remote.host, result.stderr));
+        }
+    }
+    if let Some(ref build) = build {
+        let result = remote.run(&format!("cd {} && {}", shell_quote(&dir), build));
+        if !result.success {
+            return Err(format!("Build failed:\n\n{}{}", result.stdout, result.stderr));
+        }
+    }
+
+    let unit = ServiceUnit {
+        working_dir: dir,
+        port: Some(port.clone()),
+        ..ServiceUnit::new(options.name, &start, &remote.user)
+    };
+    service::install(remote, &unit, None)?;
+    println!("Deployed {} to {}, running as {} on port {}", options.name, remote.host, unit.unit_name(), port);
+    Ok(())
+}
+
+pub struct ComposeOptions<'a> {
+    pub name: &'a str,
+    // Local compose file
+    pub file: &'a str,
+    // Local env file, uploaded as the stack's .env
+    pub env_file: Option<&'a str>,
+    // Compose service nginx proxies to, and the port it listens on inside its container
+    pub proxy: Option<(&'a str, &'a str)>,
+    // Default: the port the host's nginx vhost proxies to, else configure::DEFAULT_APP_PORT
+    pub port: Option<&'a str>,
+}
+
+// Uploads a compose file to /srv/<name> and brings the stack up, publishing the proxied
+// service on the port nginx proxies to. Services that build their image need their build
+// context too, so then the whole directory the compose file is in goes up with it.
+pub fn deploy_compose(remote: &Remote, options: &ComposeOptions) -> Result<(), String> {
+    docker::validate_name(options.name)?;
+    if let Some((service, _)) = options.proxy {
+        docker::validate_service(service)?;
+    }
+    let compose = fs::read_to_string(options.file).map_err(|e| format!("Failed to read {}: {}", options.file, e))?;
+    let dir = format!("/srv/{}", options.name);
+    let mut chain = chain::CommandChain::new()
+        .cmd(&remote.ssh_root(&format!("mkdir -p {0} && chown {1} {0}", shell_quote(&dir), remote.user)));
+    if docker::builds_images(&compose) {
+        let context = Path::new(options.file).parent().map(|p| p.display().to_string())
+            .filter(|p| !p.is_empty())
+            .unwrap_or_else(|| ".".to_string());
+        info!("{} builds images, uploading {} as their build context", options.file, context);
+        chain = chain.cmd(&sync_command(remote, &Source::Dir(context), &dir));
+    }
+    chain = chain
+        .cmd(&remote.scp_to(&shell_quote(options.file), &format!("{}/{}", dir, docker::COMPOSE_FILE)));
+    if let Some(env_file) = options.env_file {
+        chain = chain
+            .cmd(&remote.ssh(&format!("touch {0}/.env && chmod 600 {0}/.env", dir)))
+            .cmd(&remote.scp_to(&shell_quote(env_file), &format!("{}/.env", dir)));
+    }
+    let port = match options.proxy {
+        Some((service, container_port)) => {
+            let port = options.port.map(|p| p.to_string())
+                .or_else(|| vhost_port(remote))
+                .unwrap_or_else(|| configure::DEFAULT_APP_PORT.to_string());
+            let local = configure::write_local_file(&format!("{}-{}", options.name, docker::OVERRIDE_FILE),
+                                                    &docker::override_file(service, &port, container_port));
+            chain = chain
+                .cmd(&remote.scp_to(&local, &format!("{}/{}", dir, docker::OVERRIDE_FILE)))
+                .cmd(&format!("rm {}", local));
+            Some(port)
+        },
+        None => {
+            chain = chain.cmd(&remote.ssh(&format!("rm -f {}/{}", dir, docker::OVERRIDE_FILE)));
+            None
+        },
+    };
+    let res = chain.execute();
+    if let Some(ref result) = res.result {
+        if !result.success {
+            return Err(format!("Failed to copy {} to {}:\n\n{}", options.name, remote.host, result.stderr));
+        }
+    }
+
+    info!("Bringing up {} on {}", options.name, remote.host);
+    let result = remote.run_root(&docker::compose_up_command(&dir, &docker::project_name(options.name), port.is_some()));
+    if !result.success {
+        return Err(format!("docker compose failed:\n\n{}{}", result.stdout, result.stderr));
+    }
+    println!("{}", result.stdout.trim_end());
+    match port {
+        Some(port) => println!("Deployed {} to {}, proxied on port {}", options.name, remote.host, port),
+        None => println!("Deployed {} to {}", options.name, remote.host),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(files: &[&str]) -> Vec<String> {
+        files.iter().map(|f| f.to_string()).collect()
+    }
+
+    #[test]
+    fn detects_builds_from_root_files() {
+        let cargo = "[package]\nname = \"site\"\nversion = \"0.1.0\"\n";
+        assert_eq!(detect_build(&names(&["Cargo.toml", "src"]), Some(cargo)), Build::Cargo("site".to_string()));
+        assert_eq!(detect_build(&names(&["package.json"]), None), Build::Npm);
+        assert_eq!(detect_build(&names(&["Gemfile", "_config.yml"]), None), Build::Jekyll);
+        assert_eq!(detect_build(&names(&["README.md"]), None), Build::Unknown);
+        // A workspace manifest has no package to run
+        assert_eq!(detect_build(&names(&["Cargo.toml"]), Some("[workspace]\n")), Build::Unknown);
+    }
+
+    #[test]
+    fn reads_the_port_from_the_nginx_vhost() {
+        let conf = "server {\n    listen 80;\n    location / {\n        proxy_pass http://localhost:3000;\n    }\n}";
+        assert_eq!(parse_proxy_port(conf), Some("3000".to_string()));
+        assert_eq!(parse_proxy_port("server {}"), None);
+    }
+
+    #[test]
+    fn syncs_directories_and_git_refs() {
+        let remote = Remote::new("root", "cloud.example.com");
+        let rsync = sync_command(&remote, &Source::Dir("site/".to_string()), "/srv/site");
+        assert!(rsync.starts_with("rsync -az --delete --exclude .git"));
+        assert!(rsync.ends_with("-e 'ssh' 'site'/ root@cloud.example.com:/srv/site/"));
+        let git = sync_command(&remote, &Source::GitRef { repo: ".".to_string(), reference: "v1.0".to_string() }, "/srv/site");
+        assert!(git.starts_with("git -C '.' archive --format=tar 'v1.0' | ssh root@cloud.example.com 'new=$(mktemp -d '\\''/srv/site'\\''/.breezyvps-sync.XXXXXX)"));
+        let extract = extract_script("/srv/my site");
+        assert!(extract.contains("rm -rf \"$new\"/.git \"$new\"/target"));
+        assert!(extract.contains("find '/srv/my site' -mindepth 1 -maxdepth 1 ! -path \"$new\" ! -name .git ! -name target"));
+    }
+}
@@ -1,119 +1,645 @@
+use std::path::Path;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Deserializer};
+use serde::de::DeserializeOwned;
+use serde_json;
 use super::command;
+use super::command::shell_quote;
 use super::chain;
+use super::config;
+use super::dns;
+use super::hosts;
+use super::known_hosts;
 
-fn get_subdomain_from_name(name: &str) -> &str {
-    // For subdomains, we only take the first element when split by ".", this allows
-    // us to create naked domain names or use the same subdomain across domains.
-    let components: Vec<&str> = name.split(".").collect();
-    if components.len() > 2 {
-        // If first throws an error after we've checked length, panic
-        components.first().unwrap()
+#[derive(Clone, Debug, PartialEq)]
+pub struct SshKey {
+    pub id: String,
+    pub fingerprint: String,
+    pub name: String,
+}
+
+// Splits off the first n whitespace separated columns of a doctl table row, returning them
+// along with the remainder of the line, which may itself contain spaces
+fn split_columns(line: &str, n: usize) -> (Vec<&str>, &str) {
+    let mut rest = line.trim();
+    let mut columns = Vec::new();
+    while columns.len() < n && !rest.is_empty() {
+        let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        columns.push(&rest[..end]);
+        rest = rest[end..].trim_start();
+    }
+    (columns, rest)
+}
+
+// Parses `doctl compute ssh-key list --no-header --format ID,FingerPrint,Name`, name goes last
+// since it is the only column which may contain spaces
+fn parse_ssh_keys(stdout: &str) -> Vec<SshKey> {
+    stdout.lines()
+        .filter_map(|line| {
+            let (columns, name) = split_columns(line, 2);
+            if columns.len() < 2 {
+                return None;
+            }
+            Some(SshKey {
+                id: columns[0].to_string(),
+                fingerprint: columns[1].to_string(),
+                name: name.to_string(),
+            })
+        })
+        .collect()
+}
+
+pub fn list_ssh_keys() -> Result<Vec<SshKey>, String> {
+    let result = command::run_host_cmd("doctl compute ssh-key list --no-header --format ID,FingerPrint,Name");
+    if !result.success {
+        return Err(format!("Failed to list ssh keys:\n{}", result.stderr));
+    }
+    Ok(parse_ssh_keys(&result.stdout))
+}
+
+fn describe_ssh_keys(keys: &[SshKey]) -> String {
+    let lines: Vec<String> = keys.iter()
+        .map(|k| format!("\t{}\t{}\t{}", k.id, k.fingerprint, k.name))
+        .collect();
+    lines.join("\n")
+}
+
+// Every selector must match exactly one account key by ID, name or fingerprint
+pub fn select_ssh_keys<'a>(keys: &'a [SshKey], selectors: &[&str]) -> Result<Vec<&'a SshKey>, String> {
+    let mut selected: Vec<&SshKey> = Vec::new();
+    for selector in selectors {
+        let matches: Vec<&SshKey> = keys.iter()
+            .filter(|k| k.id == *selector || k.name == *selector || k.fingerprint == *selector)
+            .collect();
+        match matches.len() {
+            0 => return Err(format!("No ssh key matches '{}', available keys:\n{}", selector, describe_ssh_keys(keys))),
+            1 => if !selected.contains(&matches[0]) { selected.push(matches[0]) },
+            _ => return Err(format!("'{}' matches more than one ssh key, use an ID or fingerprint:\n{}",
+                                    selector, describe_ssh_keys(&matches.into_iter().cloned().collect::<Vec<_>>()))),
+        }
+    }
+    Ok(selected)
+}
+
+// Resolves selectors to key IDs; with no selectors every key on the account is used
+fn ssh_key_ids(selectors: &[&str]) -> Result<Vec<String>, String> {
+    let keys = list_ssh_keys()?;
+    if selectors.is_empty() {
+        warn!("No ssh keys selected, adding every key on the account");
+        return Ok(keys.into_iter().map(|k| k.id).collect());
+    }
+    Ok(select_ssh_keys(&keys, selectors)?.into_iter().map(|k| k.id.clone()).collect())
+}
+
+// Runs a doctl command with JSON output and deserializes the result
+pub fn doctl_json<T: DeserializeOwned>(args: &str) -> Result<T, String> {
+    let result = command::run_host_cmd(&format!("doctl {} --output json", args));
+    if !result.success {
+        return Err(format!("doctl {} failed:\n{}", args, result.stderr));
+    }
+    serde_json::from_str(&result.stdout)
+        .map_err(|e| format!("Couldn't parse output of doctl {}: {}", args, e))
+}
+
+// Runs a full doctl command line whose output we don't need
+pub fn run_doctl(cmd: &str) -> Result<(), String> {
+    info!("Running: {}", cmd);
+    let result = command::run_host_cmd(cmd);
+    if !result.success {
+        return Err(format!("Failed with stderr:\n\n{}", result.stderr));
+    }
+    Ok(())
+}
+
+// doctl prints empty lists as null, treat those like missing fields
+pub fn null_default<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+    where D: Deserializer<'de>, T: Default + Deserialize<'de> {
+    Option::<T>::deserialize(deserializer).map(Option::unwrap_or_default)
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct NetworkAddress {
+    pub ip_address: String,
+    #[serde(rename = "type")]
+    pub address_type: String,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Networks {
+    #[serde(default, deserialize_with = "null_default")]
+    pub v4: Vec<NetworkAddress>,
+    #[serde(default, deserialize_with = "null_default")]
+    pub v6: Vec<NetworkAddress>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Droplet {
+    pub id: u64,
+    pub name: String,
+    #[serde(default)]
+    pub status: String,
+    #[serde(default)]
+    pub created_at: String,
+    #[serde(default, deserialize_with = "null_default")]
+    pub tags: Vec<String>,
+    #[serde(default, deserialize_with = "null_default")]
+    pub networks: Networks,
+}
+
+impl Droplet {
+    fn public_address(addresses: &[NetworkAddress]) -> Option<&str> {
+        addresses.iter()
+            .find(|a| a.address_type == "public")
+            .map(|a| a.ip_address.as_str())
+    }
+
+    pub fn public_ipv4(&self) -> Option<&str> {
+        Droplet::public_address(&self.networks.v4)
+    }
+
+    pub fn public_ipv6(&self) -> Option<&str> {
+        Droplet::public_address(&self.networks.v6)
+    }
+}
+
+pub fn list_droplets() -> Result<Vec<Droplet>, String> {
+    doctl_json("compute droplet list")
+}
+
+// Droplet names aren't unique on DigitalOcean, but ours are since they double as DNS names
+pub fn find_droplet(name: &str) -> Result<Option<Droplet>, String> {
+    Ok(list_droplets()?.into_iter().find(|d| d.name == name))
+}
+
+// Everything create_droplet_by_name needs besides the name
+pub struct DropletOptions<'a> {
+    pub region: &'a str,
+    pub size: &'a str,
+    pub image: &'a str,
+    // Looked up from the account's domains when not given
+    pub domain: Option<&'a str>,
+    pub enable_backups: bool,
+    pub ipv6: bool,
+    pub ssh_keys: Vec<&'a str>,
+    pub ttl: Option<u32>,
+    pub www: bool,
+    // Local cloud-init user-data file, run at first boot
+    pub user_data: Option<&'a str>,
+}
+
+// Returns the new droplet, once it's up and has its domain records
+pub fn create_droplet_by_name(name: &str, options: &DropletOptions) -> Option<Droplet> {
+    let (domain, record_name) = match dns::resolve_hostname(name, options.domain) {
+        Ok(resolved) => resolved,
+        Err(e) => {
+            println!("{}", e);
+            return None;
+        }
+    };
+
+    let ssh_key_ids = match ssh_key_ids(&options.ssh_keys) {
+        Ok(ids) => ids,
+        Err(e) => {
+            println!("{}", e);
+            return None;
+        }
+    };
+
+    let enable_backups_string = if options.enable_backups { "--enable-backups" } else { "" };
+    let enable_ipv6_string = if options.ipv6 { "--enable-ipv6" } else { "" };
+    let user_data_string = options.user_data.map(|path| format!("--user-data-file={}", shell_quote(path))).unwrap_or_default();
+
+    let create_str = format!("doctl compute droplet create {} --image={} --region={} --size={} --ssh-keys=\"{}\" {} {} {} --wait",
+                             name,
+                             options.image,
+                             options.region,
+                             options.size,
+                             ssh_key_ids.join(","),
+                             enable_backups_string,
+                             enable_ipv6_string,
+                             user_data_string);
+
+    let res = chain::CommandChain::new()
+        .cmd(&create_str)
+        .execute();
+    if !res.result.is_some_and(|r| r.success) {
+        println!("Failed to create droplet {}", name);
+        return None;
+    }
+
+    let droplet = match find_droplet(name) {
+        Ok(Some(droplet)) => droplet,
+        Ok(None) => {
+            println!("Couldn't find droplet {} after creating it", name);
+            return None;
+        },
+        Err(e) => {
+            println!("{}", e);
+            return None;
+        }
+    };
+    if let Err(e) = dns::point_at_droplet(&domain, &record_name, &droplet, options.ttl, options.www) {
+        println!("{}", e);
+    }
+    Some(droplet)
+}
+
+// Droplets carrying this tag are never destroyed
+pub const PROTECTED_TAG: &str = "protected";
+
+pub struct DestroyOptions {
+    // Skip the confirmation prompt
+    pub yes: bool,
+    // Take a snapshot, and wait for it to finish, before destroying
+    pub snapshot: bool,
+}
+
+// e.g. "3d 4h" for a droplet created three days and four hours ago
+fn describe_age(created_at: &str, now: DateTime<Utc>) -> String {
+    let created = match DateTime::parse_from_rfc3339(created_at) {
+        Ok(created) => created.with_timezone(&Utc),
+        Err(_) => return "unknown age".to_string(),
+    };
+    let age = now.signed_duration_since(created);
+    if age.num_days() > 0 {
+        format!("{}d {}h", age.num_days(), age.num_hours() % 24)
     } else {
-        &"@"
+        format!("{}h {}m", age.num_hours(), age.num_minutes() % 60)
     }
 }
 
-pub fn create_droplet_by_name(name: &str, region: Option<&str>, size: Option<&str>, domain: Option<&str>,
-                              enable_backups: Option<&str>) {
+pub fn snapshot_droplet(droplet: &Droplet, snapshot_name: &str) -> Result<(), String> {
+    run_droplet_action(droplet, "snapshot", &format!("--snapshot-name={}", shell_quote(snapshot_name)))
+}
 
-    let ssh_key_mapping_func = |res: &command::Result, cmd_str: String| -> String {
-        let ids : Vec<&str> = res.stdout.lines().collect();
-        let new_cmd = str::replace(&cmd_str, "%ssh_keys%", &ids.join(","));
-        new_cmd.to_string()
+// Drops what hosts.toml and our known_hosts have on a destroyed droplet, so whatever gets its
+// name or IPs next isn't mistaken for it
+fn forget_host(droplet: &Droplet) -> Result<(), String> {
+    let mut ports = vec![22];
+    if let Some(port) = hosts::forget(&droplet.name)?.and_then(|entry| entry.port) {
+        ports.push(port);
+    }
+    let mut names = vec![droplet.name.as_str()];
+    names.extend(droplet.public_ipv4());
+    names.extend(droplet.public_ipv6());
+    known_hosts::forget(&names, &ports)
+}
+
+// Carries the hosts.toml entry and pinned host keys over to the droplet's new name
+fn rename_host(droplet: &Droplet, new_name: &str) -> Result<(), String> {
+    let mut ports = vec![22];
+    if let Some(entry) = hosts::forget(&droplet.name)? {
+        ports.extend(entry.port);
+        hosts::remember(new_name, entry)?;
+    }
+    let mut names = vec![new_name];
+    names.extend(droplet.public_ipv4());
+    names.extend(droplet.public_ipv6());
+    known_hosts::rename(&droplet.name, &names, &ports)
+}
+
+pub fn destroy_droplet_by_name(name: &str, domain: Option<&str>, options: &DestroyOptions) {
+    // Records are best effort, a droplet outside any of our domains can still be destroyed
+    let records = match dns::resolve_hostname(name, domain) {
+        Ok((domain, record_name)) => match dns::list_records(&domain) {
+            Ok(records) => {
+                let doomed: Vec<dns::Record> = dns::droplet_records(&records, &record_name).into_iter().cloned().collect();
+                Some((domain, doomed))
+            },
+            Err(e) => {
+                println!("{}", e);
+                return;
+            }
+        },
+        Err(e) => {
+            warn!("Not removing any domain records: {}", e);
+            None
+        }
+    };
+    let droplet = match find_droplet(name) {
+        Ok(droplet) => droplet,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
     };
 
-    let ip_address_mapping_func = |res: &command::Result, cmd_str: String| -> String {
-        let mut ip_address : Option<String> = None;
-        let res_stdout = res.stdout.clone();
-        for line in res_stdout.lines() {
-            if line.starts_with(name) {
-                debug!("Found: {}", line);
-                let fields : Vec<&str> = line.split_whitespace().collect();
-                if fields.len() < 2 {
-                    warn!("Couldn't find ip address in line: {}", line);
-                }
-                ip_address = Some(fields[1].to_string());
-                break;
+    if let Some(ref droplet) = droplet {
+        if droplet.tags.iter().any(|t| t == PROTECTED_TAG) {
+            println!("Droplet {} is tagged {}, refusing to destroy it", name, PROTECTED_TAG);
+            return;
+        }
+    }
+
+    match droplet {
+        Some(ref droplet) => println!("Droplet {} ({}), {}, created {} ago",
+                                      droplet.name,
+                                      droplet.id,
+                                      droplet.public_ipv4().unwrap_or("no public IPv4"),
+                                      describe_age(&droplet.created_at, Utc::now())),
+        None => println!("No droplet named {}", name),
+    }
+    let has_records = matches!(records, Some((_, ref doomed)) if !doomed.is_empty());
+    match records {
+        Some((ref domain, ref doomed)) if has_records => {
+            println!("Records to be removed from {}:", domain);
+            for r in doomed {
+                println!("\t{}\t{}\t{}", r.record_type, r.name, r.data);
             }
+        },
+        _ => println!("No domain records to remove"),
+    }
+    if droplet.is_none() && !has_records {
+        return;
+    }
+    if !options.yes && !command::confirm(&format!("Destroy {}?", name)) {
+        println!("Aborted");
+        return;
+    }
+
+    if let Some(ref droplet) = droplet {
+        if options.snapshot {
+            let snapshot_name = format!("{}-{}", name, Utc::now().format("%Y%m%d%H%M%S"));
+            if let Err(e) = snapshot_droplet(droplet, &snapshot_name) {
+                println!("{}, not destroying", e);
+                return;
+            }
+            println!("Saved snapshot {}", snapshot_name);
+        }
+        let res = chain::CommandChain::new()
+            .cmd(&format!("doctl compute droplet delete -f {}", droplet.id))
+            .execute();
+        if !res.result.is_some_and(|r| r.success) {
+            println!("Failed to destroy droplet {}", name);
+            return;
+        }
+        if let Err(e) = forget_host(droplet) {
+            println!("{}", e);
         }
-        if ip_address == None {
-            error!("Couldn't locate droplet in output: {}", res_stdout);
-            return "--will fail--".to_string()
+    }
+    if let Some((domain, doomed)) = records {
+        for record in doomed.iter() {
+            if let Err(e) = dns::delete_record(&domain, record) {
+                println!("{}", e);
+            }
         }
+    }
+}
 
-        let new_cmd = str::replace(&cmd_str, "%ip_address%", &ip_address.unwrap());
-        new_cmd.to_string()
-    };
+pub fn require_droplet(name: &str) -> Result<Droplet, String> {
+    find_droplet(name)?.ok_or_else(|| format!("No droplet named {}", name))
+}
 
-    let subdomain = get_subdomain_from_name(name);
-    let enable_backups_string: &str;
-    match enable_backups {
-        Some("y") => enable_backups_string = "--enable-backups",
-        Some("Y") => enable_backups_string = "--enable-backups",
-        Some(_) => enable_backups_string = "",
-        _ => enable_backups_string = ""
+pub fn refuse_protected(droplet: &Droplet, what: &str) -> Result<(), String> {
+    if droplet.tags.iter().any(|t| t == PROTECTED_TAG) {
+        return Err(format!("Droplet {} is tagged {}, refusing to {} it", droplet.name, PROTECTED_TAG, what));
     }
+    Ok(())
+}
 
-    let create_str = format!("doctl compute droplet create {} --image=ubuntu-16-04-x64 --region={} --size={} --ssh-keys=\"%ssh_keys%\" {} --wait",
-                             name,
-                             region.unwrap_or("sfo1"),
-                             size.unwrap_or("512mb"),
-                             enable_backups_string);
-    let record_str = format!("doctl compute domain records create {} --record-type=A --record-data=%ip_address% --record-name={}", domain.unwrap_or("one.haus"), subdomain);
-
-    let _ = chain::CommandChain::new()
-        .cmd("doctl compute ssh-key list --no-header --format=ID")
-        .result_mapped_cmd(&ssh_key_mapping_func, &create_str)
-        .cmd("doctl compute droplet list --format Name,PublicIPv4,PublicIPv6,Status")
-        .result_mapped_cmd(&ip_address_mapping_func, &record_str)
+// Runs `doctl compute droplet-action <action>` and waits for it to finish
+pub fn run_droplet_action(droplet: &Droplet, action: &str, extra_args: &str) -> Result<(), String> {
+    let action_cmd = format!("doctl compute droplet-action {} {} {} --wait", action, droplet.id, extra_args);
+    let res = chain::CommandChain::new()
+        .cmd(&action_cmd)
         .execute();
+    match res.result {
+        Some(ref result) if result.success => Ok(()),
+        _ => Err(format!("Failed to {} {}", action, droplet.name)),
+    }
 }
 
-pub fn destroy_droplet_by_name(name: &str, domain: Option<&str>) {
-    let subdomain = get_subdomain_from_name(name);
-    let record_id_extractor = |res: &command::Result, cmd_str: String| -> String {
-        let mut record_id : Option<String> = None;
-        let res_stdout = res.stdout.clone();
-        for line in res_stdout.lines() {
-            if line.starts_with(subdomain) {
-                debug!("Found: {}", line);
-                let fields : Vec<&str> = line.split_whitespace().collect();
-                if fields.len() < 2 {
-                    warn!("Couldn't find ip address in line: {}", line);
-                }
-                record_id = Some(fields[1].to_string());
-                break;
-            }
+pub(crate) fn print_err(result: Result<(), String>) {
+    if let Err(e) = result {
+        println!("{}", e);
+    }
+}
+
+// Actions which need nothing but the droplet: reboot, power-cycle, shutdown, power-on,
+// enable-backups and disable-backups
+pub fn droplet_action(name: &str, action: &str) {
+    print_err(require_droplet(name).and_then(|droplet| run_droplet_action(&droplet, action, "")));
+}
+
+// Resizing needs the droplet off, so it is shut down first and powered back on afterwards.
+// Resizing the disk is permanent, a CPU/RAM only resize can be undone.
+pub fn resize_droplet(name: &str, size: &str, resize_disk: bool, yes: bool) {
+    print_err(require_droplet(name).and_then(|droplet| {
+        refuse_protected(&droplet, "resize")?;
+        if resize_disk && !yes && !command::confirm(&format!("Resizing the disk of {} can't be undone, continue?", name)) {
+            return Err("Aborted".to_string());
         }
-        if record_id == None {
-            error!("Couldn't locate droplet in output: {}", res_stdout);
-            return "--will fail--".to_string()
+        let was_active = droplet.status == "active";
+        if was_active {
+            run_droplet_action(&droplet, "shutdown", "")?;
+        }
+        let disk_string = if resize_disk { "--resize-disk" } else { "" };
+        run_droplet_action(&droplet, "resize", &format!("--size={} {}", shell_quote(size), disk_string))?;
+        if was_active {
+            run_droplet_action(&droplet, "power-on", "")?;
+        }
+        Ok(())
+    }));
+}
+
+// Reinstalls the droplet from image, wiping its disk but keeping its IP addresses
+pub fn rebuild_droplet(name: &str, image: &str, yes: bool) {
+    print_err(require_droplet(name).and_then(|droplet| {
+        refuse_protected(&droplet, "rebuild")?;
+        if !yes && !command::confirm(&format!("Rebuilding {} from {} erases its disk, continue?", name, image)) {
+            return Err("Aborted".to_string());
+        }
+        run_droplet_action(&droplet, "rebuild", &format!("--image={}", shell_quote(image)))
+    }));
+}
+
+// Renames the droplet and moves its domain records (including any www CNAME) to the new name
+pub fn rename_droplet(name: &str, new_name: &str, domain: Option<&str>) {
+    print_err(require_droplet(name).and_then(|droplet| {
+        refuse_protected(&droplet, "rename")?;
+        let (old_domain, old_record) = dns::resolve_hostname(name, domain)?;
+        let (new_domain, new_record) = dns::resolve_hostname(new_name, domain)?;
+        run_droplet_action(&droplet, "rename", &format!("--droplet-name={}", shell_quote(new_name)))?;
+        rename_host(&droplet, new_name)?;
+
+        let records = dns::list_records(&old_domain)?;
+        let www = dns::droplet_records(&records, &old_record).iter().any(|r| r.record_type == "CNAME");
+        dns::point_at_droplet(&new_domain, &new_record, &droplet, None, www)?;
+        // Whatever point_at_droplet just upserted stays
+        let same_domain = if old_domain == new_domain { Some(new_record.as_str()) } else { None };
+        for record in dns::stale_droplet_records(&records, &old_record, same_domain) {
+            dns::delete_record(&old_domain, record)?;
         }
+        Ok(())
+    }));
+}
+
+// Pulls the colon separated MD5 fingerprint out of `ssh-keygen -l -E md5` output, which is
+// the form DigitalOcean reports fingerprints in
+fn parse_md5_fingerprint(stdout: &str) -> Option<String> {
+    stdout.split_whitespace()
+        .find_map(|field| field.strip_prefix("MD5:"))
+        .map(|fingerprint| fingerprint.to_string())
+}
+
+fn local_fingerprint(public_key_path: &str) -> Result<String, String> {
+    let result = command::run_host_cmd(&format!("ssh-keygen -l -E md5 -f {}", shell_quote(public_key_path)));
+    if !result.success {
+        return Err(format!("Failed to fingerprint {}:\n{}", public_key_path, result.stderr));
+    }
+    parse_md5_fingerprint(&result.stdout)
+        .ok_or_else(|| format!("Couldn't find fingerprint in output: {}", result.stdout))
+}
+
+// Creates a new passphrase-less keypair next to public_key_path, unless the private key already exists
+fn generate_keypair(public_key_path: &str, key_type: &str, comment: &str) -> Result<(), String> {
+    let private_key_path = match public_key_path.strip_suffix(".pub") {
+        Some(path) => path,
+        None => return Err(format!("Public key path {} must end in .pub to generate a keypair", public_key_path)),
+    };
+    if Path::new(private_key_path).exists() {
+        info!("{} already exists, not generating a new keypair", private_key_path);
+        return Ok(());
+    }
+    let result = command::run_host_cmd(&format!("ssh-keygen -q -t {} -N '' -C {} -f {}",
+                                                shell_quote(key_type), shell_quote(comment), shell_quote(private_key_path)));
+    if !result.success {
+        return Err(format!("Failed to generate keypair:\n{}", result.stderr));
+    }
+    Ok(())
+}
+
+pub fn list_sshkeys() {
+    match list_ssh_keys() {
+        Ok(keys) => println!("{}", describe_ssh_keys(&keys)),
+        Err(e) => println!("{}", e),
+    }
+}
 
-        let new_cmd = str::replace(&cmd_str, "%record_id%", &record_id.unwrap());
-        new_cmd.to_string()
+pub fn delete_sshkey(selector: &str) {
+    let keys = match list_ssh_keys() {
+        Ok(keys) => keys,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+    let key = match select_ssh_keys(&keys, &[selector]) {
+        Ok(selected) => selected[0],
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
     };
+    let result = command::run_host_cmd(&format!("doctl compute ssh-key delete -f {}", key.id));
+    if result.success {
+        println!("Deleted ssh key {} ({})", key.name, key.fingerprint);
+    } else {
+        println!("Failed with stderr:\n\n{}", result.stderr);
+    }
+}
 
-    let domain_name = domain.unwrap_or("one.haus");
-    let delete_droplet_cmd = format!("doctl compute droplet delete -f {}", name);
-    let list_records_cmd = format!("doctl compute domain records list {} --format Name,ID --no-header", domain_name);
-    let delete_record_cmd = format!("doctl compute domain records delete -f {} %record_id%", domain_name);
+// Uploads the public key at public_key_path as name, optionally generating a keypair of
+// generate_type first. Keys already on the account (by fingerprint) are left alone.
+pub fn import_sshkey(name: &str, public_key_path: &str, generate_type: Option<&str>) {
+    let path = config::expand_home(public_key_path);
+    let path = path.to_string_lossy();
+    if let Some(key_type) = generate_type {
+        if let Err(e) = generate_keypair(&path, key_type, name) {
+            println!("{}", e);
+            return;
+        }
+    }
 
-    // TODO: check the result heh
-    let _ = chain::CommandChain::new()
-        .cmd_nonfatal(&delete_droplet_cmd)
-        .cmd(&list_records_cmd)
-        .result_mapped_cmd(&record_id_extractor, &delete_record_cmd)
-        .execute();
+    let fingerprint = match local_fingerprint(&path) {
+        Ok(fingerprint) => fingerprint,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+    match list_ssh_keys() {
+        Ok(keys) => {
+            if let Some(existing) = keys.iter().find(|k| k.fingerprint == fingerprint) {
+                println!("Key {} is already on the account as {} ({})", fingerprint, existing.name, existing.id);
+                return;
+            }
+        },
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    }
+
+    let import_key_str = format!("doctl compute ssh-key import {} --public-key-file={}",
+                                 shell_quote(name), shell_quote(&path));
+    println!("Running command:\n\t\t{}", import_key_str);
+    let result = command::run_host_cmd(&import_key_str);
+    if !result.success {
+        println!("Failed with stderr:\n\n{}", result.stderr);
+    }
 }
 
 pub fn create_sshkey(name: &str) {
     // By default, always attempt to add a new key with [name] mapping to ~/.ssh/id_rsa.pub
-    let create_key_str = format!("doctl compute ssh-key create {} --public-key=\"$(cat ~/.ssh/id_rsa.pub)\"", name);
-    println!("Running command:\n\t\t{}", create_key_str);
-    // Create the actual droplet
-    let result = command::run_host_cmd(&create_key_str);
-    if !result.success {
-        println!("Failed with stderr:\n\n{}", result.stderr);
+    import_sshkey(name, "~/.ssh/id_rsa.pub", None);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys() -> Vec<SshKey> {
+        parse_ssh_keys("123  aa:bb:cc  laptop\n456  dd:ee:ff  work desktop\n789  11:22:33  laptop\n")
+    }
+
+    #[test]
+    fn parses_names_with_spaces() {
+        let keys = keys();
+        assert_eq!(keys.len(), 3);
+        assert_eq!(keys[1].id, "456");
+        assert_eq!(keys[1].fingerprint, "dd:ee:ff");
+        assert_eq!(keys[1].name, "work desktop");
+    }
+
+    #[test]
+    fn selects_by_id_name_or_fingerprint() {
+        let keys = keys();
+        let selected = select_ssh_keys(&keys, &["123", "work desktop", "11:22:33"]).unwrap();
+        let ids: Vec<&str> = selected.iter().map(|k| k.id.as_str()).collect();
+        assert_eq!(ids, vec!["123", "456", "789"]);
+    }
+
+    #[test]
+    fn parses_droplet_addresses() {
+        let json = r#"[{"id":1,"name":"api.example.com","status":"active","tags":null,
+            "networks":{"v4":[{"ip_address":"10.0.0.2","type":"private"},{"ip_address":"1.2.3.4","type":"public"}],"v6":null}}]"#;
+        let droplets: Vec<Droplet> = serde_json::from_str(json).unwrap();
+        assert_eq!(droplets[0].public_ipv4(), Some("1.2.3.4"));
+        assert_eq!(droplets[0].public_ipv6(), None);
+        assert!(droplets[0].tags.is_empty());
+    }
+
+    #[test]
+    fn describes_droplet_age() {
+        let now = DateTime::parse_from_rfc3339("2017-09-04T16:30:00Z").unwrap().with_timezone(&Utc);
+        assert_eq!(describe_age("2017-09-01T12:00:00Z", now), "3d 4h");
+        assert_eq!(describe_age("2017-09-04T14:00:00Z", now), "2h 30m");
+        assert_eq!(describe_age("", now), "unknown age");
+    }
+
+    #[test]
+    fn parses_md5_fingerprint() {
+        let stdout = "256 MD5:3b:9c:aa:01:02:03:04:05:06:07:08:09:0a:0b:0c:0d me@laptop (ED25519)\n";
+        assert_eq!(parse_md5_fingerprint(stdout), Some("3b:9c:aa:01:02:03:04:05:06:07:08:09:0a:0b:0c:0d".to_string()));
+        assert_eq!(parse_md5_fingerprint("garbage"), None);
+    }
+
+    #[test]
+    fn unknown_or_ambiguous_selectors_fail() {
+        let keys = keys();
+        let err = select_ssh_keys(&keys, &["nope"]).unwrap_err();
+        assert!(err.contains("work desktop"));
+        assert!(select_ssh_keys(&keys, &["laptop"]).is_err());
     }
 }
@@ -0,0 +1,275 @@
+use super::command::shell_quote;
+use super::digitalocean;
+use super::digitalocean::Droplet;
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct Record {
+    pub id: u64,
+    #[serde(rename = "type")]
+    pub record_type: String,
+    pub name: String,
+    #[serde(default)]
+    pub data: String,
+    #[serde(default)]
+    pub ttl: u32,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Domain {
+    pub name: String,
+}
+
+fn normalize(name: &str) -> String {
+    name.trim().trim_end_matches('.').to_ascii_lowercase()
+}
+
+// Record name for hostname relative to domain, "@" for the domain itself. Works for any
+// depth, so a.b.example.com in example.com is "a.b".
+pub fn relative_name(hostname: &str, domain: &str) -> Result<String, String> {
+    let hostname = normalize(hostname);
+    let domain = normalize(domain);
+    if hostname.split('.').any(|label| label.is_empty()) {
+        return Err(format!("Invalid hostname: {}", hostname));
+    }
+    if !domain.contains('.') {
+        return Err(format!("{} is a top level domain, not one we can manage", domain));
+    }
+    if hostname == domain {
+        return Ok("@".to_string());
+    }
+    match hostname.strip_suffix(&domain).and_then(|prefix| prefix.strip_suffix('.')) {
+        Some(name) => Ok(name.to_string()),
+        None => Err(format!("{} is not inside the domain {}", hostname, domain)),
+    }
+}
+
+// The most specific of domains containing hostname
+pub fn find_managed_domain<'a>(hostname: &str, domains: &'a [String]) -> Option<&'a str> {
+    domains.iter()
+        .filter(|domain| relative_name(hostname, domain).is_ok())
+        .max_by_key(|domain| domain.len())
+        .map(|domain| domain.as_str())
+}
+
+pub fn list_domains() -> Result<Vec<String>, String> {
+    let domains: Vec<Domain> = digitalocean::doctl_json("compute domain list")?;
+    Ok(domains.into_iter().map(|d| d.name).collect())
+}
+
+// Splits hostname into its domain and relative record name. The domain has to be one on the
+// account, the most specific one containing hostname unless given.
+pub fn resolve_hostname(hostname: &str, domain: Option<&str>) -> Result<(String, String), String> {
+    let domains = list_domains()?;
+    if let Some(domain) = domain {
+        let domain = normalize(domain);
+        if !domains.iter().any(|d| normalize(d) == domain) {
+            return Err(format!("{} isn't a domain on the account, add it with `doctl compute domain create`", domain));
+        }
+        return relative_name(hostname, &domain).map(|name| (domain, name));
+    }
+    match find_managed_domain(hostname, &domains) {
+        Some(domain) => relative_name(hostname, domain).map(|name| (normalize(domain), name)),
+        None => Err(format!("No domain on the account contains {}, add it with `doctl compute domain create` or pass --domain",
+                            hostname)),
+    }
+}
+
+pub fn list_records(domain: &str) -> Result<Vec<Record>, String> {
+    digitalocean::doctl_json(&format!("compute domain records list {}", domain))
+}
+
+fn filter_records<'a>(records: &'a [Record], name: Option<&str>, record_type: Option<&str>) -> Vec<&'a Record> {
+    records.iter()
+        .filter(|r| name.map_or(true, |n| r.name == n))
+        .filter(|r| record_type.map_or(true, |t| r.record_type.eq_ignore_ascii_case(t)))
+        .collect()
+}
+
+// Records whose name is exactly name, of record_type if given. Never a prefix match, "api" must not match "api2".
+pub fn find_records<'a>(records: &'a [Record], name: &str, record_type: Option<&str>) -> Vec<&'a Record> {
+    filter_records(records, Some(name), record_type)
+}
+
+fn ttl_flag(ttl: Option<u32>) -> String {
+    ttl.map(|t| format!("--record-ttl={}", t)).unwrap_or_default()
+}
+
+pub fn create_record(domain: &str, record_type: &str, name: &str, data: &str, ttl: Option<u32>) -> Result<(), String> {
+    digitalocean::run_doctl(&format!("doctl compute domain records create {} --record-type={} --record-name={} --record-data={} {}",
+                       domain, record_type, shell_quote(name), shell_quote(data), ttl_flag(ttl)))
+}
+
+pub fn update_record(domain: &str, record: &Record, data: &str, ttl: Option<u32>) -> Result<(), String> {
+    digitalocean::run_doctl(&format!("doctl compute domain records update {} --record-id={} --record-type={} --record-name={} --record-data={} {}",
+                       domain, record.id, record.record_type, shell_quote(&record.name), shell_quote(data), ttl_flag(ttl)))
+}
+
+pub fn delete_record(domain: &str, record: &Record) -> Result<(), String> {
+    digitalocean::run_doctl(&format!("doctl compute domain records delete -f {} {}", domain, record.id))
+}
+
+// Points name/record_type at data, updating an existing record in place rather than adding a second one
+pub fn upsert_record(domain: &str, record_type: &str, name: &str, data: &str, ttl: Option<u32>) -> Result<(), String> {
+    let records = list_records(domain)?;
+    let existing = find_records(&records, name, Some(record_type));
+    match existing.split_first() {
+        None => create_record(domain, record_type, name, data, ttl),
+        Some((record, others)) => {
+            if !others.is_empty() {
+                warn!("{} {} records named {}, only updating {}", existing.len(), record_type, name, record.id);
+            }
+            if record.data == data && ttl.map_or(true, |t| t == record.ttl) {
+                info!("{} record {} already points at {}", record_type, name, data);
+                return Ok(());
+            }
+            update_record(domain, record, data, ttl)
+        }
+    }
+}
+
+// Deletes every record named exactly name, only of record_type if given
+pub fn delete_records(domain: &str, name: &str, record_type: Option<&str>) -> Result<usize, String> {
+    let records = list_records(domain)?;
+    let matching = find_records(&records, name, record_type);
+    for record in matching.iter() {
+        delete_record(domain, record)?;
+    }
+    Ok(matching.len())
+}
+
+fn www_name(name: &str) -> String {
+    if name == "@" {
+        "www".to_string()
+    } else {
+        format!("www.{}", name)
+    }
+}
+
+// Absolute, with the trailing dot record data needs
+pub fn fqdn(domain: &str, name: &str) -> String {
+    if name == "@" {
+        format!("{}.", domain)
+    } else {
+        format!("{}.{}.", name, domain)
+    }
+}
+
+// A record for the droplet's IPv4 address, AAAA when it has IPv6, and optionally a www CNAME
+pub fn point_at_droplet(domain: &str, name: &str, droplet: &Droplet, ttl: Option<u32>, www: bool) -> Result<(), String> {
+    let ipv4 = droplet.public_ipv4()
+        .ok_or_else(|| format!("Droplet {} has no public IPv4 address", droplet.name))?;
+    upsert_record(domain, "A", name, ipv4, ttl)?;
+    if let Some(ipv6) = droplet.public_ipv6() {
+        upsert_record(domain, "AAAA", name, ipv6, ttl)?;
+    }
+    if www {
+        upsert_record(domain, "CNAME", &www_name(name), &fqdn(domain, name), ttl)?;
+    }
+    Ok(())
+}
+
+// The records point_at_droplet would have created for name
+pub fn droplet_records<'a>(records: &'a [Record], name: &str) -> Vec<&'a Record> {
+    let mut matching = find_records(records, name, Some("A"));
+    matching.extend(find_records(records, name, Some("AAAA")));
+    matching.extend(find_records(records, &www_name(name), Some("CNAME")));
+    matching
+}
+
+// old_name's droplet records left behind by a rename, less any that are also new_name's, given
+// when both names are in the same domain
+pub fn stale_droplet_records<'a>(records: &'a [Record], old_name: &str, new_name: Option<&str>) -> Vec<&'a Record> {
+    droplet_records(records, old_name).into_iter()
+        .filter(|r| new_name.map_or(true, |new_name| r.name != new_name && r.name != www_name(new_name)))
+        .collect()
+}
+
+// Prints ID, type, name, TTL and data, optionally only for records with exactly name and/or of record_type
+pub fn print_records(domain: &str, name: Option<&str>, record_type: Option<&str>) -> Result<(), String> {
+    let records = list_records(domain)?;
+    for r in filter_records(&records, name, record_type) {
+        println!("\t{}\t{}\t{}\t{}\t{}", r.id, r.record_type, r.name, r.ttl, r.data);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(id: u64, record_type: &str, name: &str) -> Record {
+        Record {
+            id,
+            record_type: record_type.to_string(),
+            name: name.to_string(),
+            data: "1.2.3.4".to_string(),
+            ttl: 1800,
+        }
+    }
+
+    #[test]
+    fn find_records_matches_exact_names_only() {
+        let records = vec![record(1, "A", "api2"), record(2, "A", "api"), record(3, "AAAA", "api")];
+        let ids: Vec<u64> = find_records(&records, "api", None).iter().map(|r| r.id).collect();
+        assert_eq!(ids, vec![2, 3]);
+        let ids: Vec<u64> = find_records(&records, "api", Some("aaaa")).iter().map(|r| r.id).collect();
+        assert_eq!(ids, vec![3]);
+    }
+
+    #[test]
+    fn keeps_records_a_rename_points_again() {
+        let records = vec![record(1, "A", "api"), record(2, "CNAME", "www.api"), record(3, "A", "web")];
+        let ids: Vec<u64> = stale_droplet_records(&records, "api", Some("web")).iter().map(|r| r.id).collect();
+        assert_eq!(ids, vec![1, 2]);
+        assert!(stale_droplet_records(&records, "api", Some("api")).is_empty());
+        assert_eq!(stale_droplet_records(&records, "api", None).len(), 2);
+    }
+
+    #[test]
+    fn parses_doctl_json() {
+        let json = r#"[{"id":12,"type":"A","name":"@","data":"1.2.3.4","priority":null,"port":null,"ttl":3600,"weight":null,"flags":0,"tag":""}]"#;
+        let records: Vec<Record> = ::serde_json::from_str(json).unwrap();
+        assert_eq!(records[0].record_type, "A");
+        assert_eq!(records[0].ttl, 3600);
+    }
+
+    #[test]
+    fn relative_names_at_any_depth() {
+        assert_eq!(relative_name("example.com", "example.com").unwrap(), "@");
+        assert_eq!(relative_name("api.example.com", "example.com").unwrap(), "api");
+        assert_eq!(relative_name("a.b.example.com", "example.com").unwrap(), "a.b");
+        assert_eq!(relative_name("a.b.example.com", "b.example.com").unwrap(), "a");
+        assert_eq!(relative_name("API.Example.com.", "example.com").unwrap(), "api");
+        assert_eq!(relative_name("example.co.uk", "example.co.uk").unwrap(), "@");
+        assert_eq!(relative_name("www.example.co.uk", "example.co.uk").unwrap(), "www");
+    }
+
+    #[test]
+    fn refuses_names_outside_the_domain() {
+        assert!(relative_name("api.other.com", "example.com").is_err());
+        assert!(relative_name("notexample.com", "example.com").is_err());
+        assert!(relative_name("example.com", "api.example.com").is_err());
+        assert!(relative_name("a..example.com", "example.com").is_err());
+        assert!(relative_name("example.com", "com").is_err());
+    }
+
+    #[test]
+    fn picks_the_most_specific_managed_domain() {
+        let domains = vec!["example.com".to_string(), "b.example.com".to_string(), "other.com".to_string()];
+        assert_eq!(find_managed_domain("a.b.example.com", &domains), Some("b.example.com"));
+        assert_eq!(find_managed_domain("a.example.com", &domains), Some("example.com"));
+        assert_eq!(find_managed_domain("example.net", &domains), None);
+        // Zones come from the account, so multi label suffixes need no special casing
+        let domains = vec!["example.co.uk".to_string()];
+        assert_eq!(find_managed_domain("a.example.co.uk", &domains), Some("example.co.uk"));
+        assert_eq!(find_managed_domain("other.co.uk", &domains), None);
+    }
+
+    #[test]
+    fn www_cname_targets() {
+        assert_eq!(www_name("@"), "www");
+        assert_eq!(www_name("api"), "www.api");
+        assert_eq!(fqdn("example.com", "@"), "example.com.");
+        assert_eq!(fqdn("example.com", "api"), "api.example.com.");
+    }
+}
@@ -0,0 +1,105 @@
+use super::packages::PackageManager;
+
+// Compose files generated next to the uploaded one
+pub const COMPOSE_FILE: &str = "compose.yaml";
+pub const OVERRIDE_FILE: &str = "compose.breezyvps.yaml";
+
+// Root commands installing Docker Engine with the compose plugin from Docker's own repository.
+// Docker doesn't publish for Alpine, so there it comes from the community repository.
+pub fn install_command(manager: PackageManager) -> String {
+    let packages = "docker-ce docker-ce-cli containerd.io docker-buildx-plugin docker-compose-plugin";
+    match manager {
+        PackageManager::Apt => format!("apt-get update && DEBIAN_FRONTEND=noninteractive apt-get install -y ca-certificates curl && \
+             . /etc/os-release && install -m 0755 -d /etc/apt/keyrings && \
+             curl -fsSL https://download.docker.com/linux/$ID/gpg -o /etc/apt/keyrings/docker.asc && chmod a+r /etc/apt/keyrings/docker.asc && \
+             echo \"deb [arch=$(dpkg --print-architecture) signed-by=/etc/apt/keyrings/docker.asc] https://download.docker.com/linux/$ID $VERSION_CODENAME stable\" \
+             > /etc/apt/sources.list.d/docker.list && \
+             apt-get update && DEBIAN_FRONTEND=noninteractive apt-get install -y {}", packages),
+        // dnf5 changed config-manager's syntax
+        PackageManager::Dnf => format!(". /etc/os-release && if [ \"$ID\" = fedora ]; then repo=fedora; else repo=centos; fi && \
+             url=https://download.docker.com/linux/$repo/docker-ce.repo && dnf install -y dnf-plugins-core && \
+             (dnf config-manager --add-repo $url || dnf config-manager addrepo --from-repofile=$url) && \
+             dnf install -y {}", packages),
+        PackageManager::Apk => "apk update && apk add docker docker-cli-compose".to_string(),
+    }
+}
+
+// Root command letting user run docker without sudo, from their next login
+pub fn docker_group_command(user: &str) -> String {
+    format!("usermod -aG docker {0} || addgroup {0} docker", user)
+}
+
+// Compose service names end up in YAML keys and shell commands
+pub fn validate_service(service: &str) -> Result<(), String> {
+    if !service.is_empty() && service.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.') {
+        Ok(())
+    } else {
+        Err(format!("Invalid compose service: {}", service))
+    }
+}
+
+// App names become /srv/<name>, and the compose project name
+pub fn validate_name(name: &str) -> Result<(), String> {
+    if !name.starts_with('.') && validate_service(name).is_ok() {
+        Ok(())
+    } else {
+        Err(format!("Invalid app name: {}", name))
+    }
+}
+
+// Compose project names only allow lowercase letters, digits, - and _
+pub fn project_name(name: &str) -> String {
+    name.to_lowercase().chars().map(|c| if c.is_ascii_alphanumeric() || c == '_' { c } else { '-' }).collect()
+}
+
+// Whether any service in compose builds its image, from a context relative to the compose file
+pub fn builds_images(compose: &str) -> bool {
+    compose.lines().any(|line| line.starts_with(' ') && line.trim_start().starts_with("build:"))
+}
+
+// Publishes service's container_port on host_port for nginx to proxy to. Bound to localhost,
+// since ports docker publishes skip the host firewall. Compose merges ports: lists across files
+// rather than replacing them, so this can't take back a publish in the user's own compose file.
+pub fn override_file(service: &str, host_port: &str, container_port: &str) -> String {
+    format!("services:\n  {}:\n    ports:\n      - \"127.0.0.1:{}:{}\"\n", service, host_port, container_port)
+}
+
+// Root command run in dir pulling images and bringing the stack up, removing services which
+// are no longer in the file
+pub fn compose_up_command(dir: &str, project: &str, with_override: bool) -> String {
+    let override_arg = if with_override { format!(" -f {}", OVERRIDE_FILE) } else { String::new() };
+    let compose = format!("docker compose -p {} -f {}{}", project, COMPOSE_FILE, override_arg);
+    format!("cd {} && {1} pull --ignore-buildable && {1} up -d --build --remove-orphans && {1} ps", dir, compose)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn publishes_the_proxied_port_on_localhost() {
+        assert_eq!(override_file("web", "8080", "3000"), "services:\n  web:\n    ports:\n      - \"127.0.0.1:8080:3000\"\n");
+        assert!(validate_service("web-1").is_ok());
+        assert!(validate_service("web: {}").is_err());
+        assert!(validate_name("cloud.example.com").is_ok());
+        assert!(validate_name("..").is_err());
+        assert!(validate_name("app; rm -rf /").is_err());
+        assert_eq!(project_name("Cloud.example.com"), "cloud-example-com");
+    }
+
+    #[test]
+    fn spots_services_that_build() {
+        assert!(builds_images("services:\n  web:\n    build: .\n"));
+        assert!(builds_images("services:\n  web:\n    build:\n      context: ./web\n"));
+        assert!(!builds_images("services:\n  web:\n    image: nginx\n    command: echo build: none\n"));
+    }
+
+    #[test]
+    fn brings_the_stack_up() {
+        assert_eq!(compose_up_command("/srv/api", "api", true),
+                   "cd /srv/api && docker compose -p api -f compose.yaml -f compose.breezyvps.yaml pull --ignore-buildable && \
+docker compose -p api -f compose.yaml -f compose.breezyvps.yaml up -d --build --remove-orphans && \
+docker compose -p api -f compose.yaml -f compose.breezyvps.yaml ps");
+        assert!(compose_up_command("/srv/api", "api", false).starts_with("cd /srv/api && docker compose -p api -f compose.yaml pull"));
+    }
+}
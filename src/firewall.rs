@@ -0,0 +1,641 @@
+use std::fs::File;
+use std::io::prelude::*;
+use std::net::IpAddr;
+use toml;
+use super::command;
+use super::command::shell_quote;
+use super::config;
+use super::digitalocean;
+use super::remote::OsFamily;
+
+// TCP ports open to the world by default, shared by the host iptables rules and the cloud firewall
+pub const ALLOWED_TCP_PORTS: &[u16] = &[80, 443, 22];
+
+// Sources and destinations meaning "anywhere", for both IPv4 and IPv6
+const ANYWHERE: &str = "address:0.0.0.0/0,address:::/0";
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    #[default]
+    Tcp,
+    Udp,
+}
+
+impl Protocol {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Protocol::Tcp => "tcp",
+            Protocol::Udp => "udp",
+        }
+    }
+}
+
+// Inbound traffic to port, from the listed CIDRs or from anywhere when there are none
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    pub port: u16,
+    #[serde(default)]
+    pub protocol: Protocol,
+    #[serde(default, rename = "from")]
+    pub sources: Vec<String>,
+}
+
+// What gets let in. Everything else inbound is dropped, outbound is left open.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Policy {
+    #[serde(default)]
+    pub allow: Vec<Rule>,
+    // Answer pings
+    #[serde(default)]
+    pub icmp: bool,
+    // New ssh connections allowed per source per minute, unlimited when unset
+    #[serde(default)]
+    pub ssh_rate_limit: Option<u32>,
+    #[serde(default = "default_ssh_port")]
+    pub ssh_port: u16,
+}
+
+fn default_ssh_port() -> u16 {
+    22
+}
+
+fn validate_cidr(cidr: &str) -> Result<(), String> {
+    let mut parts = cidr.splitn(2, '/');
+    let address: IpAddr = parts.next().unwrap_or("").parse()
+        .map_err(|_| format!("Invalid source address: {}", cidr))?;
+    let max_prefix = if address.is_ipv4() { 32 } else { 128 };
+    match parts.next().map(|p| p.parse::<u8>()) {
+        None => Ok(()),
+        Some(Ok(prefix)) if prefix <= max_prefix => Ok(()),
+        Some(_) => Err(format!("Invalid prefix length: {}", cidr)),
+    }
+}
+
+// "80" or "53/udp"
+pub fn parse_port_spec(spec: &str) -> Result<(u16, Protocol), String> {
+    let mut parts = spec.splitn(2, '/');
+    let port = parts.next().unwrap_or("").trim().parse()
+        .map_err(|_| format!("Invalid port: {}", spec))?;
+    let protocol = match parts.next().map(|p| p.trim().to_lowercase()) {
+        None => Protocol::Tcp,
+        Some(ref p) if p == "tcp" => Protocol::Tcp,
+        Some(ref p) if p == "udp" => Protocol::Udp,
+        Some(p) => return Err(format!("Unknown protocol: {}", p)),
+    };
+    Ok((port, protocol))
+}
+
+// "22=203.0.113.0/24" or "5432/tcp=10.0.0.1,10.0.0.2"
+pub fn parse_allow_from(spec: &str) -> Result<Rule, String> {
+    let mut parts = spec.splitn(2, '=');
+    let (port, protocol) = parse_port_spec(parts.next().unwrap_or(""))?;
+    let sources: Vec<String> = parts.next()
+        .ok_or_else(|| format!("Expected PORT=CIDR, got {}", spec))?
+        .split(',')
+        .map(|s| s.trim().to_string())
+        .collect();
+    for source in sources.iter() {
+        validate_cidr(source)?;
+    }
+    Ok(Rule { port, protocol, sources })
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Policy {
+            allow: ALLOWED_TCP_PORTS.iter()
+                .map(|&port| Rule { port, protocol: Protocol::Tcp, sources: Vec::new() })
+                .collect(),
+            icmp: false,
+            ssh_rate_limit: None,
+            ssh_port: default_ssh_port(),
+        }
+    }
+}
+
+impl Policy {
+    pub fn from_file(path: &str) -> Result<Policy, String> {
+        let path = config::expand_home(path);
+        let mut contents = String::new();
+        File::open(&path)
+            .and_then(|mut f| f.read_to_string(&mut contents))
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        Policy::parse(&contents)
+    }
+
+    pub fn parse(contents: &str) -> Result<Policy, String> {
+        let policy: Policy = toml::from_str(contents).map_err(|e| format!("Invalid policy file: {}", e))?;
+        for rule in policy.allow.iter() {
+            for source in rule.sources.iter() {
+                validate_cidr(source)?;
+            }
+        }
+        Ok(policy)
+    }
+
+    // Adds rule. A rule restricted to sources replaces any open-to-the-world rule for the same
+    // port, so --allow-from 22=office narrows ssh rather than adding to it.
+    pub fn allow(&mut self, rule: Rule) {
+        if !rule.sources.is_empty() {
+            self.allow.retain(|r| !(r.port == rule.port && r.protocol == rule.protocol && r.sources.is_empty()));
+        }
+        if !self.allow.contains(&rule) {
+            self.allow.push(rule);
+        }
+    }
+
+    pub fn allows_ssh(&self) -> bool {
+        self.allow.iter().any(|r| r.port == self.ssh_port && r.protocol == Protocol::Tcp)
+    }
+
+    // Moves ssh to port, carrying over any source restrictions from the old port
+    pub fn move_ssh(&mut self, port: u16) {
+        let old = self.ssh_port;
+        for rule in self.allow.iter_mut() {
+            if rule.port == old && rule.protocol == Protocol::Tcp {
+                rule.port = port;
+            }
+        }
+        self.ssh_port = port;
+    }
+
+    // The INPUT rules for iptables, or ip6tables when ipv6, in order, between flushing the chain
+    // and setting its policy to DROP. Sources are split by address family, so a rule limited to
+    // IPv4 sources keeps its port closed over IPv6.
+    pub fn input_rules(&self, ipv6: bool) -> Vec<String> {
+        let mut rules = vec![
+            "-p tcp --tcp-flags ALL NONE -j DROP".to_string(),
+            "-p tcp ! --syn -m state --state NEW -j DROP".to_string(),
+            "-p tcp --tcp-flags ALL ALL -j DROP".to_string(),
+            format!("-s {} -j ACCEPT", if ipv6 { "::1" } else { "127.0.0.1" }),
+        ];
+        if ipv6 {
+            // IPv6 stops working without neighbour discovery
+            for icmpv6_type in ["packet-too-big", "neighbour-solicitation", "neighbour-advertisement", "router-advertisement"].iter() {
+                rules.push(format!("-p ipv6-icmp --icmpv6-type {} -j ACCEPT", icmpv6_type));
+            }
+        }
+        if let Some(limit) = self.ssh_rate_limit {
+            rules.push(format!("-p tcp --dport {} -m state --state NEW -m recent --set --name SSH", self.ssh_port));
+            rules.push(format!("-p tcp --dport {} -m state --state NEW -m recent --update --seconds 60 --hitcount {} --name SSH -j DROP",
+                               self.ssh_port, limit));
+        }
+        for rule in self.allow.iter() {
+            let proto = rule.protocol.name();
+            if rule.sources.is_empty() {
+                rules.push(format!("-p {0} -m {0} --dport {1} -j ACCEPT", proto, rule.port));
+            }
+            for source in rule.sources.iter().filter(|s| is_ipv6(s) == ipv6) {
+                rules.push(format!("-s {0} -p {1} -m {1} --dport {2} -j ACCEPT", source, proto, rule.port));
+            }
+        }
+        if self.icmp {
+            rules.push(if ipv6 {
+                "-p ipv6-icmp --icmpv6-type echo-request -j ACCEPT".to_string()
+            } else {
+                "-p icmp --icmp-type echo-request -j ACCEPT".to_string()
+            });
+        }
+        rules.push("-m state --state ESTABLISHED,RELATED -j ACCEPT".to_string());
+        rules
+    }
+
+    // iptables-restore input for iptables, or ip6tables when ipv6. Meant for --noflush, so only
+    // INPUT is replaced and chains other tools manage (docker's FORWARD rules, say) are left alone.
+    pub fn iptables_restore(&self, ipv6: bool) -> String {
+        let mut restore = String::from("*filter\n:INPUT DROP [0:0]\n:OUTPUT ACCEPT [0:0]\n-F INPUT\n");
+        for rule in self.input_rules(ipv6) {
+            restore.push_str(&format!("-A INPUT {}\n", rule));
+        }
+        restore.push_str("COMMIT\n");
+        restore
+    }
+
+    // Files to upload before Backend::guarded_apply_script, as (remote path, contents)
+    pub fn staged_rules(&self, backend: Backend) -> Vec<(&'static str, String)> {
+        match backend {
+            Backend::Iptables => vec![
+                (STAGED_V4, self.iptables_restore(false)),
+                (STAGED_V6, self.iptables_restore(true)),
+            ],
+            Backend::Nftables => vec![(STAGED_NFT, self.nftables_ruleset())],
+        }
+    }
+
+    // The same policy as an nftables script covering IPv4 and IPv6 in one inet table. Loading it
+    // replaces only that table in one transaction, so tables other tools manage (docker's nat
+    // and filter chains, fail2ban, libvirt) are left alone.
+    pub fn nftables_ruleset(&self) -> String {
+        let mut input = vec![
+            "type filter hook input priority 0; policy drop;".to_string(),
+            "iif lo accept".to_string(),
+            "tcp flags & (fin|syn|rst|psh|ack|urg) == 0x0 drop".to_string(),
+            "tcp flags & (fin|syn|rst|psh|ack|urg) == fin|syn|rst|psh|ack|urg drop".to_string(),
+            "tcp flags & (fin|syn|rst|ack) != syn ct state new drop".to_string(),
+            "ct state established,related accept".to_string(),
+            "icmpv6 type { packet-too-big, nd-neighbor-solicit, nd-neighbor-advert, nd-router-advert } accept".to_string(),
+        ];
+        if let Some(limit) = self.ssh_rate_limit {
+            input.push(format!("tcp dport {} ct state new meter ssh4 {{ ip saddr limit rate over {}/minute }} drop", self.ssh_port, limit));
+            input.push(format!("tcp dport {} ct state new meter ssh6 {{ ip6 saddr limit rate over {}/minute }} drop", self.ssh_port, limit));
+        }
+        for rule in self.allow.iter() {
+            let proto = rule.protocol.name();
+            if rule.sources.is_empty() {
+                input.push(format!("{} dport {} accept", proto, rule.port));
+            }
+            for source in rule.sources.iter() {
+                let family = if is_ipv6(source) { "ip6" } else { "ip" };
+                input.push(format!("{} saddr {} {} dport {} accept", family, source, proto, rule.port));
+            }
+        }
+        if self.icmp {
+            input.push("icmp type echo-request accept".to_string());
+            input.push("icmpv6 type echo-request accept".to_string());
+        }
+
+        let mut ruleset = format!("#!/usr/sbin/nft -f\n# Managed by breezyvps\n\n{}\ntable inet {} {{\n    chain input {{\n",
+                                  NFT_REPLACE_TABLE, NFT_TABLE);
+        for line in input {
+            ruleset.push_str(&format!("        {}\n", line));
+        }
+        ruleset.push_str("    }\n\n    chain output {\n        type filter hook output priority 0; policy accept;\n    }\n}\n");
+        ruleset
+    }
+}
+
+fn is_ipv6(cidr: &str) -> bool {
+    cidr.contains(':')
+}
+
+// Which tool enforces the policy on the host
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Backend {
+    Iptables,
+    Nftables,
+}
+
+// Where rules are staged, and the previous rules kept, on the host during a guarded apply. Root
+// feeds these to iptables-restore and nft, so they live in a directory only root can write to
+// rather than anywhere another user could plant them.
+const STATE_DIR: &str = "/run/breezyvps";
+const STAGED_V4: &str = "/run/breezyvps/rules.v4";
+const STAGED_V6: &str = "/run/breezyvps/rules.v6";
+pub const STAGED_NFT: &str = "/run/breezyvps/rules.nft";
+const ROLLBACK_V4: &str = "/run/breezyvps/rollback.v4";
+const ROLLBACK_V6: &str = "/run/breezyvps/rollback.v6";
+const ROLLBACK_NFT: &str = "/run/breezyvps/rollback.nft";
+// Our nftables table. Declaring it before deleting it means the delete works even the first time.
+const NFT_TABLE: &str = "breezyvps";
+const NFT_REPLACE_TABLE: &str = "table inet breezyvps\ndelete table inet breezyvps\n";
+// Shortest revert timer we'll arm, anything less reverts before we can confirm
+pub const MIN_REVERT_AFTER: u32 = 10;
+// The revert timer only fires while this exists
+const PENDING_FILE: &str = "/run/breezyvps/firewall.pending";
+
+impl Backend {
+    // Run as root before uploading the staged rules with Remote::scp_to_root
+    pub fn prepare_command() -> String {
+        format!("install -d -m 700 -o root {}", STATE_DIR)
+    }
+
+    fn snapshot_command(&self) -> String {
+        match *self {
+            Backend::Iptables => format!("iptables-save > {} && ip6tables-save > {}", ROLLBACK_V4, ROLLBACK_V6),
+            Backend::Nftables => format!("(printf '{}'; nft list table inet {} 2>/dev/null) > {}",
+                                         NFT_REPLACE_TABLE.replace('\n', "\\n"), NFT_TABLE, ROLLBACK_NFT),
+        }
+    }
+
+    // Checks the staged rules, then loads them in one transaction per address family
+    fn apply_command(&self) -> String {
+        match *self {
+            Backend::Iptables => format!("iptables-restore --test --noflush < {0} && ip6tables-restore --test --noflush < {1} && \
+                                          iptables-restore --noflush < {0} && ip6tables-restore --noflush < {1}",
+                                         STAGED_V4, STAGED_V6),
+            Backend::Nftables => format!("nft -c -f {0} && nft -f {0}", STAGED_NFT),
+        }
+    }
+
+    fn revert_command(&self) -> String {
+        match *self {
+            Backend::Iptables => format!("iptables-restore < {}; ip6tables-restore < {}", ROLLBACK_V4, ROLLBACK_V6),
+            Backend::Nftables => format!("nft -f {}", ROLLBACK_NFT),
+        }
+    }
+
+    // Saves the live rules, arms a timer restoring them after timeout seconds, then applies the
+    // staged rules. Unless confirm_command runs from a fresh connection before the timer fires,
+    // the host goes back to the old rules by itself. A failed apply reverts straight away.
+    pub fn guarded_apply_script(&self, timeout: u32) -> String {
+        let timer = format!("sleep {0}; if [ -e {1} ]; then {2}; rm -f {1}; fi", timeout, PENDING_FILE, self.revert_command());
+        format!("touch {0} && {1} && (nohup sh -c {2} >/dev/null 2>&1 &) && {{ {3} || {{ {4}; rm -f {0}; exit 1; }}; }}",
+                PENDING_FILE, self.snapshot_command(), shell_quote(&timer), self.apply_command(), self.revert_command())
+    }
+
+    // Disarms the revert timer
+    pub fn confirm_command() -> String {
+        format!("rm {}", PENDING_FILE)
+    }
+
+    pub fn from_name(name: &str) -> Result<Backend, String> {
+        match name {
+            "iptables" => Ok(Backend::Iptables),
+            "nftables" => Ok(Backend::Nftables),
+            _ => Err(format!("Unknown firewall backend: {}", name)),
+        }
+    }
+}
+
+// Installs whatever reloads saved iptables rules at boot, then saves the current ones
+pub fn iptables_persist_commands(family: OsFamily) -> Vec<&'static str> {
+    match family {
+        OsFamily::Debian => vec![
+            "echo iptables-persistent iptables-persistent/autosave_v4 boolean false | debconf-set-selections",
+            "echo iptables-persistent iptables-persistent/autosave_v6 boolean false | debconf-set-selections",
+            "apt-get update && DEBIAN_FRONTEND=noninteractive apt-get install -y iptables-persistent",
+            "iptables-save > /etc/iptables/rules.v4",
+            "ip6tables-save > /etc/iptables/rules.v6",
+        ],
+        OsFamily::Fedora => vec![
+            "dnf install -y iptables-services",
+            "iptables-save > /etc/sysconfig/iptables",
+            "ip6tables-save > /etc/sysconfig/ip6tables",
+            "systemctl enable iptables ip6tables",
+        ],
+        OsFamily::Alpine => vec![
+            "apk add iptables ip6tables",
+            "/etc/init.d/iptables save",
+            "/etc/init.d/ip6tables save",
+            "rc-update add iptables default",
+            "rc-update add ip6tables default",
+        ],
+        OsFamily::Unknown => vec![],
+    }
+}
+
+// Where each distro's nftables service loads its ruleset from at boot
+pub fn nftables_config_path(family: OsFamily) -> &'static str {
+    match family {
+        OsFamily::Fedora => "/etc/sysconfig/nftables.conf",
+        OsFamily::Alpine => "/etc/nftables.nft",
+        _ => "/etc/nftables.conf",
+    }
+}
+
+// Our ruleset's drop-in, included from the distro's config so its other tables and its own
+// flush ruleset stay as they are
+const NFT_DROP_IN: &str = "/etc/nftables.d/breezyvps.nft";
+
+// Installs the staged ruleset as our drop-in and includes it from the main config unless that
+// already does, Alpine's including every /etc/nftables.d/*.nft
+pub fn nftables_persist_commands(family: OsFamily, staged: &str) -> Vec<String> {
+    let config = nftables_config_path(family);
+    let include = format!("include \"{}\"", NFT_DROP_IN);
+    vec![
+        format!("mkdir -p /etc/nftables.d && install -m 600 {} {}", staged, NFT_DROP_IN),
+        format!("touch {0} && (grep -qxF {1} {0} || grep -qF '\"/etc/nftables.d/*.nft\"' {0} || echo {1} >> {0})",
+                config, shell_quote(&include)),
+    ]
+}
+
+pub fn nftables_install_commands(family: OsFamily) -> Vec<&'static str> {
+    match family {
+        OsFamily::Debian => vec!["apt-get update && DEBIAN_FRONTEND=noninteractive apt-get install -y nftables", "systemctl enable nftables"],
+        OsFamily::Fedora => vec!["dnf install -y nftables", "systemctl enable nftables"],
+        OsFamily::Alpine => vec!["apk add nftables", "rc-update add nftables default"],
+        OsFamily::Unknown => vec![],
+    }
+}
+
+// A DigitalOcean Cloud Firewall, sitting in front of droplets at the network edge
+#[derive(Clone, Debug, Deserialize)]
+pub struct CloudFirewall {
+    pub id: String,
+    pub name: String,
+    #[serde(default)]
+    pub status: String,
+    #[serde(default, deserialize_with = "digitalocean::null_default")]
+    pub droplet_ids: Vec<u64>,
+    #[serde(default, deserialize_with = "digitalocean::null_default")]
+    pub tags: Vec<String>,
+}
+
+// doctl's rule syntax: space separated rules of comma separated key:value pairs. Cloud firewalls
+// can't rate limit, so ssh_rate_limit only applies to iptables.
+pub fn inbound_rules(policy: &Policy) -> String {
+    let mut rules: Vec<String> = policy.allow.iter()
+        .map(|rule| {
+            let sources = if rule.sources.is_empty() {
+                ANYWHERE.to_string()
+            } else {
+                let addresses: Vec<String> = rule.sources.iter().map(|s| format!("address:{}", s)).collect();
+                addresses.join(",")
+            };
+            format!("protocol:{},ports:{},{}", rule.protocol.name(), rule.port, sources)
+        })
+        .collect();
+    if policy.icmp {
+        rules.push(format!("protocol:icmp,{}", ANYWHERE));
+    }
+    rules.join(" ")
+}
+
+// Outbound is left wide open, same as the iptables OUTPUT ACCEPT policy
+pub fn outbound_rules() -> String {
+    format!("protocol:tcp,ports:all,{0} protocol:udp,ports:all,{0} protocol:icmp,{0}", ANYWHERE)
+}
+
+pub fn list_cloud_firewalls() -> Result<Vec<CloudFirewall>, String> {
+    digitalocean::doctl_json("compute firewall list")
+}
+
+pub fn print_cloud_firewalls() {
+    match list_cloud_firewalls() {
+        Ok(firewalls) => {
+            for f in firewalls {
+                let droplet_ids: Vec<String> = f.droplet_ids.iter().map(|id| id.to_string()).collect();
+                println!("\t{}\t{}\t{}\tdroplets: {}\ttags: {}", f.id, f.name, f.status, droplet_ids.join(","), f.tags.join(","));
+            }
+        },
+        Err(e) => println!("{}", e),
+    }
+}
+
+// Creates the named cloud firewall, or replaces the rules of an existing one. Droplets and tags
+// are added to whatever the firewall is already attached to, never removed.
+pub fn apply_cloud_firewall(name: &str, droplet_names: &[&str], tags: &[&str], policy: &Policy) -> Result<(), String> {
+    let droplets = digitalocean::list_droplets()?;
+    let existing = list_cloud_firewalls()?.into_iter().find(|f| f.name == name);
+
+    let mut droplet_ids: Vec<u64> = existing.as_ref().map(|f| f.droplet_ids.clone()).unwrap_or_default();
+    for droplet_name in droplet_names {
+        let droplet = droplets.iter()
+            .find(|d| d.name == *droplet_name)
+            .ok_or_else(|| format!("No droplet named {}", droplet_name))?;
+        if !droplet_ids.contains(&droplet.id) {
+            droplet_ids.push(droplet.id);
+        }
+    }
+    let mut tag_names: Vec<String> = existing.as_ref().map(|f| f.tags.clone()).unwrap_or_default();
+    for tag in tags {
+        if !tag_names.iter().any(|t| t == tag) {
+            tag_names.push(tag.to_string());
+        }
+    }
+
+    let droplet_ids: Vec<String> = droplet_ids.iter().map(|id| id.to_string()).collect();
+    let args = format!("--name={} --inbound-rules={} --outbound-rules={} --droplet-ids={} --tag-names={}",
+                       shell_quote(name),
+                       shell_quote(&inbound_rules(policy)),
+                       shell_quote(&outbound_rules()),
+                       shell_quote(&droplet_ids.join(",")),
+                       shell_quote(&tag_names.join(",")));
+    match existing {
+        Some(firewall) => digitalocean::run_doctl(&format!("doctl compute firewall update {} {}", firewall.id, args)),
+        None => digitalocean::run_doctl(&format!("doctl compute firewall create {}", args)),
+    }
+}
+
+pub fn delete_cloud_firewall(name: &str, yes: bool) -> Result<(), String> {
+    let firewall = list_cloud_firewalls()?.into_iter()
+        .find(|f| f.name == name)
+        .ok_or_else(|| format!("No cloud firewall named {}", name))?;
+    if !yes && !command::confirm(&format!("Delete cloud firewall {}?", name)) {
+        return Err("Aborted".to_string());
+    }
+    digitalocean::run_doctl(&format!("doctl compute firewall delete -f {}", firewall.id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn office_policy() -> Policy {
+        let mut policy = Policy::default();
+        policy.allow(parse_allow_from("22=203.0.113.0/24").unwrap());
+        let (port, protocol) = parse_port_spec("53/udp").unwrap();
+        policy.allow(Rule { port, protocol, sources: Vec::new() });
+        policy.icmp = true;
+        policy
+    }
+
+    #[test]
+    fn renders_doctl_rules() {
+        assert_eq!(inbound_rules(&office_policy()),
+                   "protocol:tcp,ports:80,address:0.0.0.0/0,address:::/0 \
+                    protocol:tcp,ports:443,address:0.0.0.0/0,address:::/0 \
+                    protocol:tcp,ports:22,address:203.0.113.0/24 \
+                    protocol:udp,ports:53,address:0.0.0.0/0,address:::/0 \
+                    protocol:icmp,address:0.0.0.0/0,address:::/0");
+        assert!(outbound_rules().starts_with("protocol:tcp,ports:all,address:0.0.0.0/0,address:::/0 protocol:udp"));
+    }
+
+    #[test]
+    fn default_policy_matches_the_original_iptables_rules() {
+        assert_eq!(Policy::default().iptables_restore(false), "\
+*filter
+:INPUT DROP [0:0]
+:OUTPUT ACCEPT [0:0]
+-F INPUT
+-A INPUT -p tcp --tcp-flags ALL NONE -j DROP
+-A INPUT -p tcp ! --syn -m state --state NEW -j DROP
+-A INPUT -p tcp --tcp-flags ALL ALL -j DROP
+-A INPUT -s 127.0.0.1 -j ACCEPT
+-A INPUT -p tcp -m tcp --dport 80 -j ACCEPT
+-A INPUT -p tcp -m tcp --dport 443 -j ACCEPT
+-A INPUT -p tcp -m tcp --dport 22 -j ACCEPT
+-A INPUT -m state --state ESTABLISHED,RELATED -j ACCEPT
+COMMIT
+");
+    }
+
+    #[test]
+    fn guarded_apply_arms_the_revert_timer_before_applying() {
+        let script = Backend::Nftables.guarded_apply_script(60);
+        let timer = script.find("sleep 60").unwrap();
+        let apply = script.find("nft -c -f /run/breezyvps/rules.nft").unwrap();
+        assert!(script.starts_with("touch /run/breezyvps/firewall.pending && \
+(printf 'table inet breezyvps\\ndelete table inet breezyvps\\n'; nft list table inet breezyvps 2>/dev/null) > /run/breezyvps/rollback.nft"));
+        assert!(timer < apply);
+        assert!(script.ends_with("|| { nft -f /run/breezyvps/rollback.nft; rm -f /run/breezyvps/firewall.pending; exit 1; }; }"));
+        assert!(!script.contains("/tmp/"));
+    }
+
+    #[test]
+    fn allow_from_narrows_open_rules() {
+        let policy = office_policy();
+        let ssh: Vec<&Rule> = policy.allow.iter().filter(|r| r.port == 22).collect();
+        assert_eq!(ssh.len(), 1);
+        assert_eq!(ssh[0].sources, vec!["203.0.113.0/24".to_string()]);
+        assert!(policy.input_rules(false).contains(&"-s 203.0.113.0/24 -p tcp -m tcp --dport 22 -j ACCEPT".to_string()));
+        assert!(policy.input_rules(false).contains(&"-p udp -m udp --dport 53 -j ACCEPT".to_string()));
+        // The office is IPv4 only, so ssh stays closed over IPv6
+        assert!(!policy.input_rules(true).iter().any(|r| r.contains("--dport 22")));
+        assert!(policy.input_rules(true).contains(&"-s ::1 -j ACCEPT".to_string()));
+    }
+
+    #[test]
+    fn rate_limits_ssh_before_accepting_it() {
+        let policy = Policy { ssh_rate_limit: Some(6), ..Policy::default() };
+        let rules = policy.input_rules(false);
+        let limit = rules.iter().position(|r| r.contains("--hitcount 6")).unwrap();
+        let accept = rules.iter().position(|r| r.contains("--dport 22 -j ACCEPT")).unwrap();
+        assert!(limit < accept);
+    }
+
+    #[test]
+    fn persists_nftables_as_a_drop_in() {
+        let commands = nftables_persist_commands(OsFamily::Debian, STAGED_NFT);
+        assert_eq!(commands[0], "mkdir -p /etc/nftables.d && install -m 600 /run/breezyvps/rules.nft /etc/nftables.d/breezyvps.nft");
+        assert!(commands[1].ends_with("|| echo 'include \"/etc/nftables.d/breezyvps.nft\"' >> /etc/nftables.conf)"));
+        assert!(!commands.iter().any(|c| c.contains("cp ")));
+    }
+
+    #[test]
+    fn renders_nftables_ruleset() {
+        let ruleset = office_policy().nftables_ruleset();
+        assert!(!ruleset.contains("flush ruleset"));
+        assert!(ruleset.contains("table inet breezyvps\ndelete table inet breezyvps\n\ntable inet breezyvps {\n"));
+        assert!(ruleset.contains("policy drop;"));
+        assert!(ruleset.contains("        tcp dport 80 accept\n"));
+        assert!(ruleset.contains("        ip saddr 203.0.113.0/24 tcp dport 22 accept\n"));
+        assert!(ruleset.contains("        udp dport 53 accept\n"));
+        assert!(ruleset.contains("icmp type echo-request accept"));
+        assert!(!ruleset.contains("        tcp dport 22 accept\n"));
+    }
+
+    #[test]
+    fn parses_policy_files() {
+        let policy = Policy::parse(r#"
+icmp = true
+ssh_rate_limit = 4
+
+[[allow]]
+port = 443
+
+[[allow]]
+port = 22
+from = ["203.0.113.7", "2001:db8::/32"]
+"#).unwrap();
+        assert_eq!(policy.allow.len(), 2);
+        assert_eq!(policy.allow[0].protocol, Protocol::Tcp);
+        assert!(policy.allows_ssh());
+        assert!(Policy::parse("[[allow]]\nport = 22\nfrom = [\"office\"]").is_err());
+        assert!(parse_allow_from("22=10.0.0.0/33").is_err());
+        assert!(parse_port_spec("53/sctp").is_err());
+    }
+
+    #[test]
+    fn moving_ssh_keeps_source_restrictions() {
+        let mut policy = Policy::default();
+        policy.allow(parse_allow_from("22=203.0.113.0/24").unwrap());
+        policy.move_ssh(2222);
+        assert!(policy.allows_ssh());
+        assert!(!policy.allow.iter().any(|r| r.port == 22));
+        assert!(policy.input_rules(false).contains(&"-s 203.0.113.0/24 -p tcp -m tcp --dport 2222 -j ACCEPT".to_string()));
+    }
+}
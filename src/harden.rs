@@ -0,0 +1,199 @@
+use super::command;
+use super::command::shell_quote;
+use super::configure;
+use super::firewall;
+use super::firewall::Backend;
+use super::hosts;
+use super::known_hosts;
+use super::remote::Remote;
+
+const SSHD_CONFIG: &str = "/etc/ssh/sshd_config";
+const DEFAULT_SSH_PORT: u16 = 22;
+
+pub struct HardenOptions<'a> {
+    // Sudo capable user to create and log in as from now on
+    pub user: &'a str,
+    // Move sshd here, default: leave it where it is
+    pub ssh_port: Option<u16>,
+    // Applied when the host already has a firewall up or apply_firewall is set, with ssh moved
+    // along with sshd
+    pub policy: firewall::Policy,
+    pub apply_firewall: bool,
+    pub backend: Option<Backend>,
+    pub revert_after: u32,
+}
+
+// Same rules as useradd's default NAME_REGEX, which also keeps the name safe to put in scripts
+pub fn validate_user(user: &str) -> Result<(), String> {
+    let mut chars = user.chars();
+    let valid = match chars.next() {
+        Some(c) => (c.is_ascii_lowercase() || c == '_')
+            && chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-')
+            && user.len() <= 32,
+        None => false,
+    };
+    if valid { Ok(()) } else { Err(format!("Invalid user name: {}", user)) }
+}
+
+// The deploy user has to be someone other than root, since sshd stops letting root in
+pub fn validate_deploy_user(user: &str, login: &str) -> Result<(), String> {
+    validate_user(user)?;
+    if user == "root" || (login == "root" && user == login) {
+        return Err("The deploy user can't be root, root logins get turned off".to_string());
+    }
+    Ok(())
+}
+
+// Run as root: creates user with passwordless sudo and from_user's authorized keys. Accounts
+// without a password are unlocked ("*" rather than "!") since Alpine's sshd refuses locked ones.
+pub fn create_user_script(user: &str, from_user: &str) -> String {
+    let sudoers = format!("/etc/sudoers.d/breezyvps-{}", user);
+    let mut steps = vec![
+        format!("(id -u {0} >/dev/null 2>&1 || useradd -m -s /bin/bash {0} || adduser -D -s /bin/sh {0})", user),
+        format!("sed -i 's/^{0}:!:/{0}:*:/' /etc/shadow", user),
+        "(command -v sudo >/dev/null || apt-get install -y sudo || dnf install -y sudo || apk add sudo)".to_string(),
+        format!("mkdir -p /etc/sudoers.d && echo '{} ALL=(ALL) NOPASSWD:ALL' > {1} && chmod 440 {1}", user, sudoers),
+        format!("home=$(eval echo ~{}) && mkdir -p \"$home/.ssh\"", user),
+    ];
+    if user != from_user {
+        steps.push(format!("cat $(eval echo ~{})/.ssh/authorized_keys >> \"$home/.ssh/authorized_keys\"", from_user));
+    }
+    steps.push("sort -u -o \"$home/.ssh/authorized_keys\" \"$home/.ssh/authorized_keys\"".to_string());
+    steps.push(format!("chown -R {} \"$home/.ssh\" && chmod 700 \"$home/.ssh\" && chmod 600 \"$home/.ssh/authorized_keys\"", user));
+    steps.join(" && ")
+}
+
+// Our settings, kept at the top of sshd_config where they win over anything set further down
+// or in included files. ports replaces the configured ports when not empty.
+pub fn sshd_block(ports: &[u16]) -> String {
+    let mut block = String::from("# BEGIN breezyvps\nPermitRootLogin no\nPasswordAuthentication no\nChallengeResponseAuthentication no\n");
+    for port in ports {
+        block.push_str(&format!("Port {}\n", port));
+    }
+    block.push_str("# END breezyvps\n");
+    block
+}
+
+// Run as root: swaps in block for any previous one, checks the result with sshd -t before
+// putting it in place, then reloads sshd. Existing sessions survive the reload.
+pub fn sshd_config_script(ports: &[u16]) -> String {
+    let port_edit = if ports.is_empty() { "" } else { " -e 's/^Port /#Port /'" };
+    format!("{{ printf '%s' {block}; sed -e '/^# BEGIN breezyvps$/,/^# END breezyvps$/d'{port_edit} {config}; }} > {config}.breezyvps && \
+             /usr/sbin/sshd -t -f {config}.breezyvps && cp {config} {config}.breezyvps.bak && mv {config}.breezyvps {config} && \
+             (systemctl reload sshd || systemctl reload ssh || rc-service sshd reload) && \
+             if systemctl is-active --quiet ssh.socket 2>/dev/null; then systemctl daemon-reload && systemctl restart ssh.socket; fi",
+            block = shell_quote(&sshd_block(ports)), port_edit = port_edit, config = SSHD_CONFIG)
+}
+
+// Logs in over a new connection and checks sudo works without a password
+fn verify_login(remote: &Remote) -> bool {
+    command::run_host_cmd(&remote.ssh_fresh(10, "sudo -n true")).success
+}
+
+fn run_step(remote: &Remote, what: &str, script: &str) -> Result<(), String> {
+    info!("{} on {}", what, remote.host);
+    let result = remote.run_root(script);
+    if !result.success {
+        return Err(format!("{} failed:\n\n{}", what, result.stderr));
+    }
+    Ok(())
+}
+
+// Creates the deploy user, turns off root and password logins and optionally moves sshd,
+// checking at every step that a fresh connection still gets in before closing the old way in.
+// Records the user and port in hosts.toml and returns the remote to use from now on.
+pub fn harden(remote: &Remote, options: &HardenOptions) -> Result<Remote, String> {
+    validate_deploy_user(options.user, &remote.user)?;
+    let current_port = remote.port.unwrap_or(DEFAULT_SSH_PORT);
+    let new_port = options.ssh_port.filter(|&port| port != current_port);
+
+    run_step(remote, &format!("Creating {}", options.user), &create_user_script(options.user, &remote.user))?;
+    let admin = Remote::new(options.user, &remote.host).with_port(remote.port).with_known_hosts(remote.known_hosts.clone());
+    if !verify_login(&admin) {
+        return Err(format!("Couldn't log in to {} as {} with sudo, leaving sshd alone", remote.host, options.user));
+    }
+
+    let firewall = options.apply_firewall || configure::firewall_active(&admin);
+    let mut policy = options.policy.clone();
+    policy.move_ssh(current_port);
+
+    let admin = match new_port {
+        None => {
+            let ports = if current_port == DEFAULT_SSH_PORT { vec![] } else { vec![current_port] };
+            run_step(&admin, "Configuring sshd", &sshd_config_script(&ports))?;
+            if firewall && !configure::setup_iptables(&admin, &policy, options.backend, options.revert_after) {
+                return Err("Firewall rules were reverted".to_string());
+            }
+            admin
+        },
+        Some(new_port) => {
+            // Listen on both ports until the new one is proven reachable through any firewall
+            run_step(&admin, "Configuring sshd", &sshd_config_script(&[current_port, new_port]))?;
+            let mut moved_policy = policy.clone();
+            moved_policy.move_ssh(new_port);
+            if firewall {
+                let mut both = policy.clone();
+                for rule in moved_policy.allow.iter().filter(|r| r.port == new_port) {
+                    both.allow(rule.clone());
+                }
+                if !configure::setup_iptables(&admin, &both, options.backend, options.revert_after) {
+                    return Err(format!("Firewall rules were reverted, sshd listens on both {} and {}", current_port, new_port));
+                }
+            }
+            let moved = admin.clone().with_port(Some(new_port));
+            if moved.known_hosts.is_some() {
+                known_hosts::pin_moved_port(&moved.host, current_port, new_port)?;
+            }
+            if !verify_login(&moved) {
+                return Err(format!("Couldn't reach sshd on port {}, it still listens on {} too. Is a cloud firewall in the way?",
+                                   new_port, current_port));
+            }
+            run_step(&moved, "Closing the old ssh port", &sshd_config_script(&[new_port]))?;
+            if firewall && !configure::setup_iptables(&moved, &moved_policy, options.backend, options.revert_after) {
+                return Err(format!("Firewall rules were reverted, port {} is still open", current_port));
+            }
+            moved
+        },
+    };
+    if !verify_login(&admin) {
+        return Err(format!("Couldn't log back in to {} as {}, check sshd on the console", admin.host, admin.user));
+    }
+
+    hosts::remember(&admin.host, hosts::HostEntry { user: Some(admin.user.clone()), port: admin.port })?;
+    println!("{} hardened, breezyvps now connects as {}{}", admin.host, admin.user,
+             admin.port.map(|p| format!(" on port {}", p)).unwrap_or_default());
+    Ok(admin)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validates_user_names() {
+        assert!(validate_user("deploy").is_ok());
+        assert!(validate_user("_svc-1").is_ok());
+        assert!(validate_user("Deploy").is_err());
+        assert!(validate_user("1deploy").is_err());
+        assert!(validate_user("de ploy;rm").is_err());
+        assert!(validate_user("").is_err());
+        assert!(validate_deploy_user("root", "root").is_err());
+        assert!(validate_deploy_user("root", "deploy").is_err());
+        assert!(validate_deploy_user("deploy", "deploy").is_ok());
+    }
+
+    #[test]
+    fn sshd_block_only_sets_ports_when_asked() {
+        assert!(!sshd_block(&[]).contains("Port"));
+        assert!(sshd_block(&[22, 2222]).ends_with("Port 22\nPort 2222\n# END breezyvps\n"));
+        assert!(sshd_block(&[]).starts_with("# BEGIN breezyvps\nPermitRootLogin no\nPasswordAuthentication no\n"));
+        assert!(!sshd_config_script(&[]).contains("#Port"));
+        assert!(sshd_config_script(&[2222]).contains("-e 's/^Port /#Port /'"));
+    }
+
+    #[test]
+    fn copies_keys_only_from_another_user() {
+        assert!(create_user_script("deploy", "root").contains("cat $(eval echo ~root)/.ssh/authorized_keys"));
+        assert!(!create_user_script("deploy", "deploy").contains("cat $(eval echo"));
+    }
+}
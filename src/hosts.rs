@@ -0,0 +1,116 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::PathBuf;
+use toml;
+use super::config;
+use super::known_hosts;
+use super::remote::Remote;
+
+// How to reach one host, learned by commands like configure harden
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct HostEntry {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+}
+
+// Layout of hosts.toml: one [hosts."<name>"] table per host
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct HostsFile {
+    #[serde(default)]
+    pub hosts: BTreeMap<String, HostEntry>,
+}
+
+// Kept next to config.toml, but written by breezyvps rather than by hand
+pub fn hosts_path() -> Option<PathBuf> {
+    config::config_path().map(|path| path.with_file_name("hosts.toml"))
+}
+
+pub fn parse(contents: &str) -> Result<HostsFile, String> {
+    toml::from_str(contents).map_err(|e| format!("Invalid hosts file: {}", e))
+}
+
+impl HostsFile {
+    // A missing or unreadable file is treated as empty, with a warning for the latter
+    pub fn load() -> HostsFile {
+        let path = match hosts_path() {
+            Some(ref path) if path.exists() => path.clone(),
+            _ => return HostsFile::default(),
+        };
+        let mut contents = String::new();
+        let parsed = File::open(&path)
+            .and_then(|mut f| f.read_to_string(&mut contents))
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))
+            .and_then(|_| parse(&contents));
+        parsed.unwrap_or_else(|e| {
+            warn!("{}", e);
+            HostsFile::default()
+        })
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        let path = hosts_path().ok_or_else(|| "Can't tell where to keep hosts.toml, set $HOME".to_string())?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+        }
+        let contents = toml::to_string(self).map_err(|e| format!("Failed to serialize hosts: {}", e))?;
+        File::create(&path)
+            .and_then(|mut f| f.write_all(contents.as_bytes()))
+            .map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+    }
+
+    // How to reach host: an explicit user wins, then what we recorded for the host, then
+    // default_user from the config
+    pub fn remote(&self, host: &str, explicit_user: Option<&str>, default_user: &str) -> Remote {
+        let entry = self.hosts.get(host).cloned().unwrap_or_default();
+        let user = explicit_user
+            .map(|u| u.to_string())
+            .or(entry.user)
+            .unwrap_or_else(|| default_user.to_string());
+        Remote::new(&user, host).with_port(entry.port).with_known_hosts(known_hosts::managed_file())
+    }
+}
+
+// Records how to reach host from now on
+pub fn remember(host: &str, entry: HostEntry) -> Result<(), String> {
+    let mut hosts = HostsFile::load();
+    hosts.hosts.insert(host.to_string(), entry);
+    hosts.save()
+}
+
+// Forgets host, returning what we knew about it
+pub fn forget(host: &str) -> Result<Option<HostEntry>, String> {
+    let mut hosts = HostsFile::load();
+    let entry = hosts.hosts.remove(host);
+    if entry.is_some() {
+        hosts.save()?;
+    }
+    Ok(entry)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_dotted_host_names() {
+        let mut hosts = HostsFile::default();
+        hosts.hosts.insert("cloud.example.com".to_string(), HostEntry { user: Some("deploy".to_string()), port: Some(2222) });
+        hosts.hosts.insert("other.example.com".to_string(), HostEntry { user: None, port: Some(22) });
+        let parsed = parse(&toml::to_string(&hosts).unwrap()).unwrap();
+        assert_eq!(parsed.hosts, hosts.hosts);
+    }
+
+    #[test]
+    fn explicit_user_beats_recorded_user_beats_config() {
+        let hosts = parse("[hosts.\"cloud.example.com\"]\nuser = \"deploy\"\nport = 2222\n").unwrap();
+        let remote = hosts.remote("cloud.example.com", None, "root");
+        assert_eq!((remote.user.as_str(), remote.port), ("deploy", Some(2222)));
+        assert_eq!(hosts.remote("cloud.example.com", Some("admin"), "root").user, "admin");
+        let remote = hosts.remote("new.example.com", None, "root");
+        assert_eq!((remote.user.as_str(), remote.port), ("root", None));
+    }
+}
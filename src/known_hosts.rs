@@ -0,0 +1,141 @@
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::prelude::*;
+use std::path::PathBuf;
+use super::command;
+use super::hosts;
+
+// Host keys of the droplets we create, kept next to hosts.toml so a recycled IP or name never
+// trips over a destroyed droplet's key in ~/.ssh/known_hosts
+pub fn known_hosts_path() -> Option<PathBuf> {
+    hosts::hosts_path().map(|path| path.with_file_name("known_hosts"))
+}
+
+// For Remote::with_known_hosts
+pub fn managed_file() -> Option<String> {
+    known_hosts_path().map(|path| path.display().to_string())
+}
+
+// How known_hosts names a host, with the port only when it isn't 22
+pub fn known_host_name(name: &str, port: u16) -> String {
+    if port == 22 { name.to_string() } else { format!("[{}]:{}", name, port) }
+}
+
+// Turns ssh-keyscan output into known_hosts lines for every name the host goes by
+pub fn known_hosts_lines(scan: &str, names: &[&str], port: u16) -> Vec<String> {
+    let names: Vec<String> = names.iter().map(|name| known_host_name(name, port)).collect();
+    scan.lines()
+        .filter(|line| !line.trim().is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.split_once(' ').map(|(_, key)| format!("{} {}", names.join(","), key.trim())))
+        .collect()
+}
+
+// ssh-keyscan's output for host, empty when sshd didn't offer any keys
+pub fn scan(host: &str, port: u16) -> String {
+    let result = command::run_host_cmd(&format!("ssh-keyscan -T 5 -p {} {}", port, command::shell_quote(host)));
+    if result.stdout.lines().any(|line| !line.starts_with('#') && !line.trim().is_empty()) { result.stdout } else { String::new() }
+}
+
+// The keys in ssh-keyscan output, whatever host they were scanned from
+fn scanned_keys(scan: &str) -> Vec<String> {
+    let mut keys: Vec<String> = known_hosts_lines(scan, &[""], 22).into_iter().map(|line| line.trim().to_string()).collect();
+    keys.sort();
+    keys
+}
+
+// Pins host's keys on to_port once sshd offers the very keys it offers on from_port, as when
+// configure harden moves sshd
+pub fn pin_moved_port(host: &str, from_port: u16, to_port: u16) -> Result<(), String> {
+    let (from, to) = (scan(host, from_port), scan(host, to_port));
+    if from.is_empty() || scanned_keys(&from) != scanned_keys(&to) {
+        return Err(format!("{} offers different host keys on ports {} and {}, not trusting {}", host, from_port, to_port, to_port));
+    }
+    record(&to, &[host], to_port)
+}
+
+fn require_path() -> Result<PathBuf, String> {
+    known_hosts_path().ok_or_else(|| "Can't tell where to keep known_hosts, set $HOME".to_string())
+}
+
+// Drops every key for names on any of ports
+pub fn forget(names: &[&str], ports: &[u16]) -> Result<(), String> {
+    let path = require_path()?;
+    if !path.exists() {
+        return Ok(());
+    }
+    for name in names {
+        for port in ports {
+            let result = command::run_host_cmd(&format!("ssh-keygen -R {} -f {}",
+                                                         command::shell_quote(&known_host_name(name, *port)),
+                                                         command::shell_quote(&path.display().to_string())));
+            if !result.success {
+                return Err(format!("Failed to remove {} from {}: {}", name, path.display(), result.stderr.trim()));
+            }
+        }
+    }
+    // ssh-keygen leaves the previous contents behind as known_hosts.old
+    let _ = fs::remove_file(path.with_file_name("known_hosts.old"));
+    Ok(())
+}
+
+// Moves the keys pinned for from onto names, as when the droplet is renamed
+pub fn rename(from: &str, names: &[&str], ports: &[u16]) -> Result<(), String> {
+    let path = require_path()?;
+    if !path.exists() {
+        return Ok(());
+    }
+    let path_string = command::shell_quote(&path.display().to_string());
+    for port in ports {
+        // ssh-keygen -F prints matching lines in ssh-keyscan's format, or fails when there are none
+        let result = command::run_host_cmd(&format!("ssh-keygen -F {} -f {}",
+                                                     command::shell_quote(&known_host_name(from, *port)), path_string));
+        if result.success {
+            record(&result.stdout, names, *port)?;
+        }
+    }
+    forget(&[from], ports)
+}
+
+// Replaces whatever we had for names with the host's current keys
+pub fn record(scan: &str, names: &[&str], port: u16) -> Result<(), String> {
+    let path = require_path()?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+    }
+    forget(names, &[port])?;
+    let mut file = OpenOptions::new().create(true).append(true).open(&path)
+        .map_err(|e| format!("Failed to open {}: {}", path.display(), e))?;
+    for line in known_hosts_lines(scan, names, port) {
+        writeln!(file, "{}", line).map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compares_keys_across_ports() {
+        assert_eq!(scanned_keys("[cloud.one.haus]:2222 ssh-ed25519 AAAAC3Nza\n"), scanned_keys("# banner\ncloud.one.haus ssh-ed25519 AAAAC3Nza\n"));
+        assert_ne!(scanned_keys("cloud.one.haus ssh-ed25519 AAAAC3Nza\n"), scanned_keys("cloud.one.haus ssh-ed25519 AAAAother\n"));
+    }
+
+    #[test]
+    fn names_keys_for_every_alias() {
+        let scan = "# 203.0.113.7:22 SSH-2.0-OpenSSH_8.9p1\n203.0.113.7 ssh-ed25519 AAAAC3Nza\n203.0.113.7 ecdsa-sha2-nistp256 AAAAE2Vj\n";
+        assert_eq!(known_hosts_lines(scan, &["203.0.113.7", "cloud.one.haus"], 22), vec![
+            "203.0.113.7,cloud.one.haus ssh-ed25519 AAAAC3Nza".to_string(),
+            "203.0.113.7,cloud.one.haus ecdsa-sha2-nistp256 AAAAE2Vj".to_string(),
+        ]);
+        assert_eq!(known_hosts_lines("[cloud.one.haus]:2222 ssh-ed25519 AAAAC3Nza\n", &["cloud.one.haus"], 2222),
+                   vec!["[cloud.one.haus]:2222 ssh-ed25519 AAAAC3Nza".to_string()]);
+    }
+
+    #[test]
+    fn renames_looked_up_keys() {
+        let lookup = "# Host [old.one.haus]:2222 found: line 2 \n[old.one.haus]:2222,[203.0.113.7]:2222 ssh-ed25519 AAAAC3Nza\n";
+        assert_eq!(known_hosts_lines(lookup, &["new.one.haus", "203.0.113.7"], 2222),
+                   vec!["[new.one.haus]:2222,[203.0.113.7]:2222 ssh-ed25519 AAAAC3Nza".to_string()]);
+    }
+}
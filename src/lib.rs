@@ -1,17 +1,41 @@
 #[macro_use]
 extern crate log;
+extern crate chrono;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde;
+extern crate serde_json;
+extern crate toml;
 
+pub mod base;
+pub mod cloudinit;
 pub mod command;
+pub mod config;
+pub mod databases;
 pub mod digitalocean;
+pub mod dns;
+pub mod docker;
+pub mod firewall;
+pub mod harden;
+pub mod hosts;
+pub mod known_hosts;
 pub mod configure;
+pub mod deploy;
 pub mod chain;
+pub mod packages;
+pub mod readiness;
+pub mod remote;
+pub mod runtimes;
+pub mod service;
+pub mod snapshot;
+pub mod updates;
 
 #[cfg(test)]
 mod tests {
 
     extern crate simplelog;
-    use std::sync::{Once, ONCE_INIT};
-    static SYNC_OBJ: Once = ONCE_INIT;
+    use std::sync::Once;
+    static SYNC_OBJ: Once = Once::new();
     use super::chain;
     use super::command;
     use self::simplelog::{Config, TermLogger, WriteLogger, CombinedLogger, LogLevelFilter};
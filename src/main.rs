@@ -3,19 +3,145 @@ extern crate clap;
 extern crate breezyvps;
 extern crate simplelog;
 
+use breezyvps::config::Config as BreezyConfig;
+use breezyvps::databases::Engine;
+use breezyvps::hosts::HostsFile;
+use breezyvps::remote::Remote;
 use simplelog::{Config, TermLogger, WriteLogger, CombinedLogger, LogLevelFilter};
 use std::fs::File;
 
-fn sc_doctl(doctl_matches: &clap::ArgMatches) {
+// Command line --domain wins over the configured default
+fn domain_arg<'a>(matches: &'a clap::ArgMatches, config: &'a BreezyConfig) -> Option<&'a str> {
+    matches.value_of("domain").or(config.domain.as_deref())
+}
+
+// Repeated --ssh-key flags replace the configured ssh_keys list
+fn ssh_key_args<'a>(matches: &'a clap::ArgMatches, config: &'a BreezyConfig) -> Vec<&'a str> {
+    match matches.values_of("ssh_key") {
+        Some(values) => values.collect(),
+        None => config.ssh_keys.iter().map(|k| k.as_str()).collect(),
+    }
+}
+
+fn ttl_arg(matches: &clap::ArgMatches) -> Result<Option<u32>, String> {
+    match matches.value_of("ttl") {
+        Some(ttl) => ttl.parse().map(Some).map_err(|_| format!("Invalid ttl: {}", ttl)),
+        None => Ok(None),
+    }
+}
+
+// --timezone, --locale and --swap for configure base, shared with create_droplet --base
+fn base_args<'a>(matches: &'a clap::ArgMatches, hostname: &'a str) -> Result<breezyvps::base::BaseOptions<'a>, String> {
+    Ok(breezyvps::base::BaseOptions {
+        hostname,
+        timezone: matches.value_of("timezone").unwrap_or(breezyvps::base::DEFAULT_TIMEZONE),
+        locale: matches.value_of("locale").unwrap_or(breezyvps::base::DEFAULT_LOCALE),
+        swap_mb: breezyvps::base::parse_swap_size(matches.value_of("swap").unwrap_or(breezyvps::base::DEFAULT_SWAP))?,
+    })
+}
+
+// --user-data as is, else #cloud-config generated from --cloud-config, --package and --runcmd
+fn user_data_arg(matches: &clap::ArgMatches, name: &str) -> Result<Option<String>, String> {
+    if let Some(path) = matches.value_of("user_data") {
+        return Ok(Some(path.to_string()));
+    }
+    let mut cloud_config = match matches.value_of("cloud_config") {
+        Some(path) => breezyvps::cloudinit::CloudConfig::from_file(path)?,
+        None => breezyvps::cloudinit::CloudConfig::default(),
+    };
+    cloud_config.packages.extend(matches.values_of("package").into_iter().flatten().map(|p| p.to_string()));
+    cloud_config.runcmd.extend(matches.values_of("runcmd").into_iter().flatten().map(|c| c.to_string()));
+    if cloud_config.is_empty() {
+        return Ok(None);
+    }
+    breezyvps::cloudinit::write_user_data(name, &cloud_config.render()?).map(Some)
+}
+
+fn sc_doctl(doctl_matches: &clap::ArgMatches, config: &BreezyConfig) {
     if let Some(create_droplet_matches) = doctl_matches.subcommand_matches("create_droplet") {
         if let Some(name) = create_droplet_matches.value_of("name") {
-            breezyvps::digitalocean::create_droplet_by_name(
-                name,
-                // Both are unwrapped safely with defaults [sfo1, 512mb]
-                create_droplet_matches.value_of("region"),
-                create_droplet_matches.value_of("size"),
-                create_droplet_matches.value_of("domain"),
-                create_droplet_matches.value_of("backups"));
+            let domain = domain_arg(create_droplet_matches, config);
+            let ttl = match ttl_arg(create_droplet_matches) {
+                Ok(ttl) => ttl,
+                Err(e) => {
+                    println!("{}", e);
+                    return;
+                }
+            };
+            // The droplet's full DNS name, as create_droplet_by_name resolves it
+            let hostname = if create_droplet_matches.is_present("base") {
+                match breezyvps::dns::resolve_hostname(name, domain) {
+                    Ok((domain, record_name)) => breezyvps::dns::fqdn(&domain, &record_name).trim_end_matches('.').to_string(),
+                    Err(e) => {
+                        println!("{}", e);
+                        return;
+                    }
+                }
+            } else {
+                name.to_string()
+            };
+            let base = match base_args(create_droplet_matches, &hostname) {
+                Ok(base) => base,
+                Err(e) => {
+                    println!("{}", e);
+                    return;
+                }
+            };
+            let user_data = match user_data_arg(create_droplet_matches, name) {
+                Ok(user_data) => user_data,
+                Err(e) => {
+                    println!("{}", e);
+                    return;
+                }
+            };
+            let options = breezyvps::digitalocean::DropletOptions {
+                region: create_droplet_matches.value_of("region").unwrap_or(&config.region),
+                size: create_droplet_matches.value_of("size").unwrap_or(&config.size),
+                image: create_droplet_matches.value_of("image").unwrap_or(&config.image),
+                domain,
+                enable_backups: matches!(create_droplet_matches.value_of("backups"), Some("y") | Some("Y")),
+                ipv6: create_droplet_matches.is_present("ipv6"),
+                ssh_keys: ssh_key_args(create_droplet_matches, config),
+                ttl,
+                www: create_droplet_matches.is_present("www"),
+                user_data: user_data.as_deref(),
+            };
+            let droplet = breezyvps::digitalocean::create_droplet_by_name(name, &options);
+            // Generated user-data is only needed until doctl has passed it on
+            if let (Some(path), false) = (user_data.as_ref(), create_droplet_matches.is_present("user_data")) {
+                let _ = std::fs::remove_file(path);
+            }
+            let droplet = match droplet {
+                Some(droplet) => droplet,
+                None => return,
+            };
+            let (wait_cloud_init, run_base) = (create_droplet_matches.is_present("wait_cloud_init"), create_droplet_matches.is_present("base"));
+            if !wait_cloud_init && !run_base && !create_droplet_matches.is_present("wait_ssh") {
+                return;
+            }
+            // DNS may not have caught up yet, and fresh droplets only let root in
+            let remote = match droplet.public_ipv4() {
+                Some(ip) => Remote::new("root", ip).with_known_hosts(breezyvps::known_hosts::managed_file()),
+                None => {
+                    println!("{} has no public IPv4 address to connect to", name);
+                    return;
+                }
+            };
+            if let Err(e) = breezyvps::readiness::wait_for_ssh(&remote, &[name], std::time::Duration::from_secs(300)) {
+                println!("{}", e);
+                return;
+            }
+            if wait_cloud_init {
+                if let Err(e) = breezyvps::cloudinit::wait_for_completion(&remote) {
+                    println!("{}", e);
+                    return;
+                }
+            }
+            if run_base {
+                if let Err(e) = breezyvps::base::configure_base(&remote, &base) {
+                    println!("{}", e);
+                }
+            }
         } else {
             println!("Missing required name parameter!");
         }
@@ -23,31 +149,310 @@ fn sc_doctl(doctl_matches: &clap::ArgMatches) {
     }
     if let Some(destroy_droplet_matches) = doctl_matches.subcommand_matches("destroy_droplet") {
         if let Some(name) = destroy_droplet_matches.value_of("name") {
-            breezyvps::digitalocean::destroy_droplet_by_name(name,
-                destroy_droplet_matches.value_of("domain"));
+            let domain = domain_arg(destroy_droplet_matches, config);
+            let options = breezyvps::digitalocean::DestroyOptions {
+                yes: destroy_droplet_matches.is_present("yes"),
+                snapshot: destroy_droplet_matches.is_present("snapshot"),
+            };
+            breezyvps::digitalocean::destroy_droplet_by_name(name, domain, &options);
+        } else {
+            println!("Missing required name parameter!");
+        }
+        return;
+    }
+    // Subcommand name and the droplet-action it runs
+    let simple_actions = [
+        ("reboot", "reboot"),
+        ("power_cycle", "power-cycle"),
+        ("shutdown", "shutdown"),
+        ("power_on", "power-on"),
+    ];
+    for &(subcommand, action) in simple_actions.iter() {
+        if let Some(action_matches) = doctl_matches.subcommand_matches(subcommand) {
+            if let Some(name) = action_matches.value_of("name") {
+                breezyvps::digitalocean::droplet_action(name, action);
+            } else {
+                println!("Missing required name parameter!");
+            }
+            return;
+        }
+    }
+    if let Some(backups_matches) = doctl_matches.subcommand_matches("backups") {
+        if let (Some(name), Some(state)) = (backups_matches.value_of("name"), backups_matches.value_of("state")) {
+            let action = if state == "on" { "enable-backups" } else { "disable-backups" };
+            breezyvps::digitalocean::droplet_action(name, action);
+        } else {
+            println!("Missing required name or state parameter!");
+        }
+        return;
+    }
+    if let Some(resize_matches) = doctl_matches.subcommand_matches("resize") {
+        if let (Some(name), Some(size)) = (resize_matches.value_of("name"), resize_matches.value_of("size")) {
+            breezyvps::digitalocean::resize_droplet(name, size,
+                resize_matches.is_present("disk"),
+                resize_matches.is_present("yes"));
+        } else {
+            println!("Missing required name or size parameter!");
+        }
+        return;
+    }
+    if let Some(rebuild_matches) = doctl_matches.subcommand_matches("rebuild") {
+        if let Some(name) = rebuild_matches.value_of("name") {
+            breezyvps::digitalocean::rebuild_droplet(name,
+                rebuild_matches.value_of("image").unwrap_or(&config.image),
+                rebuild_matches.is_present("yes"));
         } else {
             println!("Missing required name parameter!");
         }
         return;
     }
+    if let Some(rename_matches) = doctl_matches.subcommand_matches("rename") {
+        if let (Some(name), Some(new_name)) = (rename_matches.value_of("name"), rename_matches.value_of("new_name")) {
+            breezyvps::digitalocean::rename_droplet(name, new_name, domain_arg(rename_matches, config));
+        } else {
+            println!("Missing required name or new_name parameter!");
+        }
+        return;
+    }
+    if let Some(sshkey_matches) = doctl_matches.subcommand_matches("sshkey") {
+        sc_sshkey(sshkey_matches);
+        return;
+    }
     if let Some(create_ssh_key_matches) = doctl_matches.subcommand_matches("create_sshkey") {
         if let Some(name) = create_ssh_key_matches.value_of("name") {
             breezyvps::digitalocean::create_sshkey(name);
         } else {
             println!("Missing required sshkey name parameter!");
         }
+    }
+}
+
+fn sc_sshkey(sshkey_matches: &clap::ArgMatches) {
+    if sshkey_matches.subcommand_matches("list").is_some() {
+        breezyvps::digitalocean::list_sshkeys();
+        return;
+    }
+    if let Some(delete_matches) = sshkey_matches.subcommand_matches("delete") {
+        if let Some(key) = delete_matches.value_of("key") {
+            breezyvps::digitalocean::delete_sshkey(key);
+        } else {
+            println!("Missing required key parameter!");
+        }
+        return;
+    }
+    if let Some(import_matches) = sshkey_matches.subcommand_matches("import") {
+        if let Some(name) = import_matches.value_of("name") {
+            let generate_type = if import_matches.is_present("generate") {
+                Some(import_matches.value_of("type").unwrap_or("ed25519"))
+            } else {
+                None
+            };
+            // A generated key lands next to ssh-keygen's own default for its type
+            let default_path = match generate_type {
+                Some(key_type) => format!("~/.ssh/id_{}.pub", key_type),
+                None => "~/.ssh/id_rsa.pub".to_string(),
+            };
+            breezyvps::digitalocean::import_sshkey(
+                name,
+                import_matches.value_of("path").unwrap_or(&default_path),
+                generate_type);
+        } else {
+            println!("Missing required sshkey name parameter!");
+        }
+    }
+}
+
+fn sc_dns(dns_matches: &clap::ArgMatches, config: &BreezyConfig) {
+    let (name, sub_matches) = dns_matches.subcommand();
+    let sub_matches = match sub_matches {
+        Some(sub_matches) => sub_matches,
+        None => {
+            println!("{}", dns_matches.usage());
+            return;
+        }
+    };
+    let domain = match domain_arg(sub_matches, config) {
+        Some(domain) => domain,
+        None => {
+            println!("Missing domain, pass --domain or set domain in the config file!");
+            return;
+        }
+    };
+    let ttl = match ttl_arg(sub_matches) {
+        Ok(ttl) => ttl,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+    let record_name = sub_matches.value_of("name").unwrap_or("");
+    let record_type = sub_matches.value_of("type");
+    let data = sub_matches.value_of("data").unwrap_or("");
+
+    let result = match name {
+        "list" => breezyvps::dns::print_records(domain, sub_matches.value_of("name"), record_type),
+        "create" => breezyvps::dns::create_record(domain, record_type.unwrap_or("A"), record_name, data, ttl),
+        "update" => breezyvps::dns::upsert_record(domain, record_type.unwrap_or("A"), record_name, data, ttl),
+        "delete" => breezyvps::dns::delete_records(domain, record_name, record_type)
+            .map(|count| println!("Deleted {} record(s)", count)),
+        _ => Ok(()),
+    };
+    if let Err(e) = result {
+        println!("{}", e);
+    }
+}
+
+fn sc_snapshot(snapshot_matches: &clap::ArgMatches) {
+    if let Some(list_matches) = snapshot_matches.subcommand_matches("list") {
+        if let Some(name) = list_matches.value_of("name") {
+            breezyvps::snapshot::print_snapshots(name);
+        } else {
+            println!("Missing required name parameter!");
+        }
+        return;
+    }
+    if let Some(backups_matches) = snapshot_matches.subcommand_matches("backups") {
+        if let Some(name) = backups_matches.value_of("name") {
+            breezyvps::snapshot::print_backups(name);
+        } else {
+            println!("Missing required name parameter!");
+        }
+        return;
+    }
+    if let Some(create_matches) = snapshot_matches.subcommand_matches("create") {
+        if let Some(name) = create_matches.value_of("name") {
+            breezyvps::snapshot::create_snapshot(name, create_matches.value_of("snapshot_name"));
+        } else {
+            println!("Missing required name parameter!");
+        }
+        return;
+    }
+    if let Some(restore_matches) = snapshot_matches.subcommand_matches("restore") {
+        if let (Some(name), Some(snapshot)) = (restore_matches.value_of("name"), restore_matches.value_of("snapshot")) {
+            breezyvps::snapshot::restore_snapshot(name, snapshot, restore_matches.is_present("yes"));
+        } else {
+            println!("Missing required name or snapshot parameter!");
+        }
+        return;
+    }
+    if let Some(delete_matches) = snapshot_matches.subcommand_matches("delete") {
+        if let (Some(name), Some(snapshot)) = (delete_matches.value_of("name"), delete_matches.value_of("snapshot")) {
+            breezyvps::snapshot::delete_snapshot(name, snapshot, delete_matches.is_present("yes"));
+        } else {
+            println!("Missing required name or snapshot parameter!");
+        }
+        return;
+    }
+    if let Some(prune_matches) = snapshot_matches.subcommand_matches("prune") {
+        let keep = match prune_matches.value_of("keep").unwrap_or("").parse() {
+            Ok(keep) => keep,
+            Err(_) => {
+                println!("Missing or invalid --keep parameter!");
+                return;
+            }
+        };
+        let names: Vec<&str> = prune_matches.values_of("name").map(|v| v.collect()).unwrap_or_default();
+        breezyvps::snapshot::prune_snapshots(&names, keep, prune_matches.is_present("dry_run"));
+    }
+}
+
+// Firewall policy from --policy (or the default 80,443,22), plus any --allow, --allow-from,
+// --icmp, --ssh-rate-limit and --ssh-port flags
+fn policy_args(matches: &clap::ArgMatches) -> Result<breezyvps::firewall::Policy, String> {
+    let mut policy = match matches.value_of("policy") {
+        Some(path) => breezyvps::firewall::Policy::from_file(path)?,
+        None => breezyvps::firewall::Policy::default(),
+    };
+    for spec in matches.values_of("allow").into_iter().flatten() {
+        let (port, protocol) = breezyvps::firewall::parse_port_spec(spec)?;
+        policy.allow(breezyvps::firewall::Rule { port, protocol, sources: Vec::new() });
+    }
+    for spec in matches.values_of("allow_from").into_iter().flatten() {
+        policy.allow(breezyvps::firewall::parse_allow_from(spec)?);
+    }
+    if matches.is_present("icmp") {
+        policy.icmp = true;
+    }
+    if let Some(limit) = matches.value_of("ssh_rate_limit") {
+        policy.ssh_rate_limit = Some(limit.parse().map_err(|_| format!("Invalid ssh rate limit: {}", limit))?);
+    }
+    if let Some(port) = matches.value_of("ssh_port") {
+        policy.move_ssh(port.parse().map_err(|_| format!("Invalid ssh port: {}", port))?);
+    }
+    Ok(policy)
+}
+
+// Short enough to be worth it, long enough for a fresh ssh connection to confirm the new rules
+fn revert_after_arg(matches: &clap::ArgMatches) -> Result<u32, String> {
+    let seconds = matches.value_of("revert_after").unwrap_or("60");
+    match seconds.parse() {
+        Ok(seconds) if seconds >= breezyvps::firewall::MIN_REVERT_AFTER => Ok(seconds),
+        Ok(_) => Err(format!("--revert-after has to be at least {} seconds", breezyvps::firewall::MIN_REVERT_AFTER)),
+        Err(_) => Err(format!("Invalid --revert-after: {}", seconds)),
+    }
+}
+
+// The flags policy_args reads, for firewall apply, setup_iptables and harden. harden moves the
+// ssh rules itself, along with sshd.
+fn policy_arg_list<'a, 'b>(ssh_port: bool) -> Vec<clap::Arg<'a, 'b>> {
+    let mut args = vec![
+        clap::Arg::with_name("policy").long("policy").takes_value(true)
+            .help("TOML policy file with [[allow]] port/protocol/from tables, icmp and ssh_rate_limit"),
+        clap::Arg::with_name("allow").long("allow").takes_value(true).multiple(true).number_of_values(1)
+            .help("Also allow PORT[/udp] from anywhere, repeatable"),
+        clap::Arg::with_name("allow_from").long("allow-from").takes_value(true).multiple(true).number_of_values(1)
+            .help("Only allow PORT[/udp] from CIDR[,CIDR], e.g. 22=203.0.113.0/24, repeatable"),
+        clap::Arg::with_name("icmp").long("icmp").help("Answer pings"),
+        clap::Arg::with_name("ssh_rate_limit").long("ssh-rate-limit").takes_value(true)
+            .help("Drop sources opening more than this many ssh connections a minute"),
+    ];
+    if ssh_port {
+        args.push(clap::Arg::with_name("ssh_port").long("ssh-port").takes_value(true)
+            .help("Port sshd listens on, its rules move there from 22"));
+    }
+    args
+}
+
+fn sc_firewall(firewall_matches: &clap::ArgMatches) {
+    if firewall_matches.subcommand_matches("list").is_some() {
+        breezyvps::firewall::print_cloud_firewalls();
+        return;
+    }
+    if let Some(apply_matches) = firewall_matches.subcommand_matches("apply") {
+        if let Some(name) = apply_matches.value_of("name") {
+            let droplets: Vec<&str> = apply_matches.values_of("droplet").map(|v| v.collect()).unwrap_or_default();
+            let tags: Vec<&str> = apply_matches.values_of("tag").map(|v| v.collect()).unwrap_or_default();
+            let result = policy_args(apply_matches)
+                .and_then(|policy| breezyvps::firewall::apply_cloud_firewall(name, &droplets, &tags, &policy));
+            if let Err(e) = result {
+                println!("{}", e);
+            }
+        } else {
+            println!("Missing required name parameter!");
+        }
         return;
     }
+    if let Some(delete_matches) = firewall_matches.subcommand_matches("delete") {
+        if let Some(name) = delete_matches.value_of("name") {
+            if let Err(e) = breezyvps::firewall::delete_cloud_firewall(name, delete_matches.is_present("yes")) {
+                println!("{}", e);
+            }
+        } else {
+            println!("Missing required name parameter!");
+        }
+    }
 }
 
-fn sc_configure(configure_matches: &clap::ArgMatches) {
+fn sc_configure(configure_matches: &clap::ArgMatches, config: &BreezyConfig) {
+    let hosts = HostsFile::load();
+    let user = configure_matches.value_of("user");
     if let Some(nginx_matches) = configure_matches.subcommand_matches("nginx") {
         if let Some(host) = nginx_matches.value_of("host") {
-            // Default 8080
-            let port = nginx_matches.value_of("port").unwrap_or("8080");
-            breezyvps::configure::install_nginx(host);
-            breezyvps::configure::add_nginx_host(host, port);
-            breezyvps::configure::install_letsencrypt_cert(host);
+            let remote = hosts.remote(host, user, &config.user);
+            let port = nginx_matches.value_of("port").unwrap_or(breezyvps::configure::DEFAULT_APP_PORT);
+            breezyvps::configure::install_nginx(&remote);
+            breezyvps::configure::add_nginx_host(&remote, port);
+            breezyvps::configure::install_letsencrypt_cert(&remote);
         } else {
             println!("Missing required host parameter!");
         }
@@ -55,7 +460,15 @@ fn sc_configure(configure_matches: &clap::ArgMatches) {
     }
     if let Some(rust_matches) = configure_matches.subcommand_matches("rust") {
         if let Some(host) = rust_matches.value_of("host") {
-            breezyvps::configure::install_rust(host);
+            let remote = hosts.remote(host, user, &config.user);
+            let options = breezyvps::runtimes::RustOptions {
+                user: rust_matches.value_of("for_user"),
+                toolchain: rust_matches.value_of("toolchain"),
+                profile: rust_matches.value_of("rustup_profile"),
+                components: rust_matches.values_of("component").map(|v| v.collect()).unwrap_or_default(),
+                targets: rust_matches.values_of("target").map(|v| v.collect()).unwrap_or_default(),
+            };
+            breezyvps::configure::install_rust(&remote, &options);
         } else {
             println!("Missing required host parameter!");
         }
@@ -63,7 +476,8 @@ fn sc_configure(configure_matches: &clap::ArgMatches) {
     }
     if let Some(python_matches) = configure_matches.subcommand_matches("python") {
         if let Some(host) = python_matches.value_of("host") {
-            breezyvps::configure::install_python(host);
+            let remote = hosts.remote(host, user, &config.user);
+            breezyvps::configure::install_python(&remote, python_matches.value_of("version"), python_matches.value_of("venv"));
         } else {
             println!("Missing required host parameter!");
         }
@@ -71,7 +485,8 @@ fn sc_configure(configure_matches: &clap::ArgMatches) {
     }
     if let Some(jekyll_matches) = configure_matches.subcommand_matches("jekyll") {
         if let Some(host) = jekyll_matches.value_of("host") {
-            breezyvps::configure::install_jekyll(host);
+            let remote = hosts.remote(host, user, &config.user);
+            breezyvps::configure::install_jekyll(&remote, jekyll_matches.value_of("ruby_version"));
         } else {
             println!("Missing required host parameter!");
         }
@@ -79,7 +494,8 @@ fn sc_configure(configure_matches: &clap::ArgMatches) {
     }
     if let Some(renew_matches) = configure_matches.subcommand_matches("renew") {
         if let Some(host) = renew_matches.value_of("host") {
-            breezyvps::configure::renew_cert(host);
+            let remote = hosts.remote(host, user, &config.user);
+            breezyvps::configure::renew_cert(&remote);
         } else {
             println!("Missing required host parameter!");
         }
@@ -87,7 +503,162 @@ fn sc_configure(configure_matches: &clap::ArgMatches) {
     }
     if let Some(iptables_matches) = configure_matches.subcommand_matches("setup_iptables") {
         if let Some(host) = iptables_matches.value_of("host") {
-            breezyvps::configure::setup_iptables(host);
+            let remote = hosts.remote(host, user, &config.user);
+            let backend = match iptables_matches.value_of("backend") {
+                Some(name) => match breezyvps::firewall::Backend::from_name(name) {
+                    Ok(backend) => Some(backend),
+                    Err(e) => {
+                        println!("{}", e);
+                        return;
+                    }
+                },
+                None => None,
+            };
+            let revert_after = match revert_after_arg(iptables_matches) {
+                Ok(seconds) => seconds,
+                Err(e) => {
+                    println!("{}", e);
+                    return;
+                }
+            };
+            match policy_args(iptables_matches) {
+                Ok(policy) => {
+                    breezyvps::configure::setup_iptables(&remote, &policy, backend, revert_after);
+                },
+                Err(e) => println!("{}", e),
+            }
+        } else {
+            println!("Missing required host parameter!");
+        }
+        return;
+    }
+    for &(subcommand, engine) in [("postgres", Engine::Postgres), ("mysql", Engine::Mysql), ("redis", Engine::Redis)].iter() {
+        if let Some(db_matches) = configure_matches.subcommand_matches(subcommand) {
+            if let Some(host) = db_matches.value_of("host") {
+                let remote = hosts.remote(host, user, &config.user);
+                let database = db_matches.value_of("database").unwrap_or("app");
+                let options = breezyvps::databases::DatabaseOptions {
+                    database,
+                    user: db_matches.value_of("db_user").unwrap_or(database),
+                    app: db_matches.value_of("app"),
+                };
+                if let Err(e) = breezyvps::databases::provision(&remote, engine, &options) {
+                    println!("{}", e);
+                }
+            } else {
+                println!("Missing required host parameter!");
+            }
+            return;
+        }
+    }
+    if let Some(service_matches) = configure_matches.subcommand_matches("service") {
+        if let (Some(host), Some(name), Some(exec)) = (service_matches.value_of("host"), service_matches.value_of("name"), service_matches.value_of("exec")) {
+            let remote = hosts.remote(host, user, &config.user);
+            let local_env_file = service_matches.value_of("env_file");
+            let mut unit = breezyvps::service::ServiceUnit::new(name, exec, service_matches.value_of("run_as").unwrap_or(&remote.user));
+            if let Some(dir) = service_matches.value_of("dir") {
+                unit.working_dir = dir.to_string();
+            }
+            if let Some(restart) = service_matches.value_of("restart") {
+                unit.restart = restart.to_string();
+            }
+            unit.port = service_matches.value_of("port").map(|p| p.to_string());
+            match breezyvps::service::install(&remote, &unit, local_env_file) {
+                Ok(()) => {
+                    if !breezyvps::service::print_status(&remote, name) {
+                        println!("{} isn't running, see `journalctl -u {}` on the host", unit.unit_name(), unit.unit_name());
+                    }
+                },
+                Err(e) => println!("{}", e),
+            }
+        } else {
+            println!("Missing required host, name or exec parameter!");
+        }
+        return;
+    }
+    if let Some(harden_matches) = configure_matches.subcommand_matches("harden") {
+        if let Some(host) = harden_matches.value_of("host") {
+            let remote = hosts.remote(host, user, &config.user);
+            let ssh_port = match harden_matches.value_of("new_ssh_port").map(|p| p.parse()) {
+                Some(Ok(port)) => Some(port),
+                Some(Err(_)) => {
+                    println!("Invalid --ssh-port parameter!");
+                    return;
+                },
+                None => None,
+            };
+            let backend = match harden_matches.value_of("backend").map(breezyvps::firewall::Backend::from_name) {
+                Some(Ok(backend)) => Some(backend),
+                Some(Err(e)) => {
+                    println!("{}", e);
+                    return;
+                },
+                None => None,
+            };
+            let revert_after = match revert_after_arg(harden_matches) {
+                Ok(seconds) => seconds,
+                Err(e) => {
+                    println!("{}", e);
+                    return;
+                }
+            };
+            let policy = match policy_args(harden_matches) {
+                Ok(policy) => policy,
+                Err(e) => {
+                    println!("{}", e);
+                    return;
+                }
+            };
+            let options = breezyvps::harden::HardenOptions {
+                user: harden_matches.value_of("deploy_user").unwrap_or("deploy"),
+                ssh_port,
+                policy,
+                apply_firewall: harden_matches.is_present("firewall"),
+                backend,
+                revert_after,
+            };
+            if let Err(e) = breezyvps::harden::harden(&remote, &options) {
+                println!("{}", e);
+            }
+        } else {
+            println!("Missing required host parameter!");
+        }
+        return;
+    }
+    if let Some(updates_matches) = configure_matches.subcommand_matches("updates") {
+        if let Some(host) = updates_matches.value_of("host") {
+            let remote = hosts.remote(host, user, &config.user);
+            let window = match updates_matches.value_of("reboot_window").map(breezyvps::updates::RebootWindow::parse) {
+                Some(Ok(window)) => Some(window),
+                Some(Err(e)) => {
+                    println!("{}", e);
+                    return;
+                },
+                None => None,
+            };
+            if let Err(e) = breezyvps::updates::configure_updates(&remote, window) {
+                println!("{}", e);
+            }
+        } else {
+            println!("Missing required host parameter!");
+        }
+        return;
+    }
+    if let Some(base_matches) = configure_matches.subcommand_matches("base") {
+        if let Some(host) = base_matches.value_of("host") {
+            let remote = hosts.remote(host, user, &config.user);
+            if let Err(e) = base_args(base_matches, host).and_then(|options| breezyvps::base::configure_base(&remote, &options)) {
+                println!("{}", e);
+            }
+        } else {
+            println!("Missing required host parameter!");
+        }
+        return;
+    }
+    if let Some(docker_matches) = configure_matches.subcommand_matches("docker") {
+        if let Some(host) = docker_matches.value_of("host") {
+            let remote = hosts.remote(host, user, &config.user);
+            breezyvps::configure::install_docker(&remote);
         } else {
             println!("Missing required host parameter!");
         }
@@ -95,7 +666,8 @@ fn sc_configure(configure_matches: &clap::ArgMatches) {
     }
     if let Some(sqlite_matches) = configure_matches.subcommand_matches("sqlite3") {
         if let Some(host) = sqlite_matches.value_of("host") {
-            breezyvps::configure::install_sqlite3(host);
+            let remote = hosts.remote(host, user, &config.user);
+            breezyvps::configure::install_sqlite3(&remote);
         } else {
             println!("Missing required host parameter!");
         }
@@ -103,12 +675,67 @@ fn sc_configure(configure_matches: &clap::ArgMatches) {
     }
     if let Some(nodejs_matches) = configure_matches.subcommand_matches("nodejs") {
         if let Some(host) = nodejs_matches.value_of("host") {
-            breezyvps::configure::install_nodejs(host);
+            let remote = hosts.remote(host, user, &config.user);
+            breezyvps::configure::install_nodejs(&remote, nodejs_matches.value_of("version"), nodejs_matches.is_present("nvm"));
         } else {
             println!("Missing required host parameter!");
         }
+    }
+}
+
+fn sc_deploy(deploy_matches: &clap::ArgMatches, config: &BreezyConfig) {
+    if let Some(compose_matches) = deploy_matches.subcommand_matches("compose") {
+        if let (Some(host), Some(file)) = (compose_matches.value_of("host"), compose_matches.value_of("file")) {
+            let remote = HostsFile::load().remote(host, compose_matches.value_of("user"), &config.user);
+            let options = breezyvps::deploy::ComposeOptions {
+                name: compose_matches.value_of("name").unwrap_or(host),
+                file,
+                env_file: compose_matches.value_of("env_file"),
+                proxy: compose_matches.value_of("service").map(|s| (s, compose_matches.value_of("container_port").unwrap_or(breezyvps::configure::DEFAULT_APP_PORT))),
+                port: compose_matches.value_of("port"),
+            };
+            if let Err(e) = breezyvps::deploy::deploy_compose(&remote, &options) {
+                println!("{}", e);
+            }
+        } else {
+            println!("Missing required host or file parameter!");
+        }
         return;
     }
+    let user = deploy_matches.value_of("user");
+    if let Some(host) = deploy_matches.value_of("host") {
+        let remote = HostsFile::load().remote(host, user, &config.user);
+        let path = deploy_matches.value_of("path").unwrap_or(".");
+        let source = match deploy_matches.value_of("git_ref") {
+            Some(reference) => breezyvps::deploy::Source::GitRef { repo: path.to_string(), reference: reference.to_string() },
+            None => breezyvps::deploy::Source::Dir(path.to_string()),
+        };
+        let options = breezyvps::deploy::DeployOptions {
+            name: deploy_matches.value_of("name").unwrap_or(host),
+            source,
+            dir: deploy_matches.value_of("dir"),
+            port: deploy_matches.value_of("port"),
+            build: deploy_matches.value_of("build"),
+            skip_build: deploy_matches.is_present("no_build"),
+            start: deploy_matches.value_of("start"),
+        };
+        if let Err(e) = breezyvps::deploy::deploy(&remote, &options) {
+            println!("{}", e);
+        }
+    } else {
+        println!("Missing required host parameter!");
+    }
+}
+
+fn sc_upgrade(upgrade_matches: &clap::ArgMatches, config: &BreezyConfig) {
+    if let Some(host) = upgrade_matches.value_of("host") {
+        let remote = HostsFile::load().remote(host, upgrade_matches.value_of("user"), &config.user);
+        if let Err(e) = breezyvps::updates::upgrade(&remote, upgrade_matches.is_present("reboot")) {
+            println!("{}", e);
+        }
+    } else {
+        println!("Missing required host parameter!");
+    }
 }
 
 fn main() {
@@ -124,7 +751,9 @@ fn main() {
         (version: "0.2.1")
         (author: "Michael Xu <michaeljxu11@gmail.com>")
         (about: "One stop shop for common command line goodness")
+        (@setting VersionlessSubcommands)
         (@arg verbose: -v ... "Enable verbose output")
+        (@arg profile: -p --profile +takes_value "Named profile from ~/.config/breezyvps/config.toml")
         (@subcommand doctl =>
             (about: "Doctl wrapper")
             (author: "Michael Xu <michaeljxu11@gmail.com>")
@@ -134,65 +763,351 @@ fn main() {
                 (@arg name: +required "Full DNS name of the droplet, must be unique. e.g. cloud.one.haus, one.haus")
                 (@arg region: -r --region +takes_value "Which region? [sfo1, nyc1, etc..]")
                 (@arg size: -s --size +takes_value "Which size droplet? [512mb, 1gb, 2gb, 4gb, 8gb, 16gb, 32gb, 48gb, 64gb]")
-                (@arg domain: -d --domain +takes_value "Which domain name? [best.haus,log.haus,swarm.link,swarmlink.com,util.in]")
+                (@arg image: -i --image +takes_value "Which image? (default: ubuntu-16-04-x64)")
+                (@arg domain: -d --domain +takes_value "Which domain name? (default: domain from config, else the account domain containing name)")
                 (@arg backups: -b --backups +takes_value "[y/n (default)] Should backups be enabled ($1/month)")
+                (@arg ipv6: --ipv6 "Enable IPv6 and add an AAAA record")
+                (@arg ttl: --ttl +takes_value "TTL in seconds for the domain records (default: 1800)")
+                (@arg www: --www "Also add a www CNAME pointing at the droplet")
+                (@arg ssh_key: -k --("ssh-key") +takes_value +multiple number_of_values(1) "SSH key name, ID or fingerprint to add, repeatable (default: ssh_keys from config, else every key)")
+                (@arg user_data: --("user-data") +takes_value conflicts_with[cloud_config package runcmd] "cloud-init user-data file to run at first boot")
+                (@arg cloud_config: --("cloud-config") +takes_value "TOML file of packages, users, files and runcmd to generate cloud-init user-data from")
+                (@arg package: --package +takes_value +multiple number_of_values(1) "Package for cloud-init to install at first boot, repeatable")
+                (@arg runcmd: --runcmd +takes_value +multiple number_of_values(1) "Command for cloud-init to run at first boot, repeatable")
+                (@arg wait_ssh: --("wait-ssh") "Wait up to 5 minutes for ssh logins, adding the host key to breezyvps' known_hosts. Implied by --wait-cloud-init and --base")
+                (@arg wait_cloud_init: --("wait-cloud-init") "Wait for cloud-init to finish")
+                (@arg base: --base "Then set the hostname, timezone, locale, swap and time sync as configure base does")
+                (@arg timezone: --timezone +takes_value requires[base] "Timezone for --base e.g. America/Los_Angeles (default: UTC)")
+                (@arg locale: --locale +takes_value requires[base] "Locale for --base (default: en_US.UTF-8)")
+                (@arg swap: --swap +takes_value requires[base] "Swap file size for --base e.g. 512M, 2G or 0 for none (default: 1G)")
             )
             (@subcommand destroy_droplet =>
-                (about: "Destroy a droplet by name")
+                (about: "Destroy a droplet by name, along with its domain records. Droplets tagged protected are refused.")
                 (@arg name: +required "Name of the droplet to destroy completely")
-                (@arg domain: -d --domain +takes_value "Domain name, default one.haus")
+                (@arg yes: -y --yes "Don't ask for confirmation")
+                (@arg snapshot: --snapshot "Take a snapshot, and wait for it, before destroying")
+                (@arg domain: -d --domain +takes_value "Domain name (default: domain from config, else the account domain containing name)")
+            )
+            (@subcommand reboot =>
+                (about: "Reboot a droplet")
+                (@arg name: +required "Name of the droplet")
+            )
+            (@subcommand power_cycle =>
+                (about: "Power cycle a droplet, like pulling the plug")
+                (@arg name: +required "Name of the droplet")
+            )
+            (@subcommand shutdown =>
+                (about: "Gracefully shut down a droplet")
+                (@arg name: +required "Name of the droplet")
+            )
+            (@subcommand power_on =>
+                (about: "Power on a droplet")
+                (@arg name: +required "Name of the droplet")
+            )
+            (@subcommand resize =>
+                (about: "Resize a droplet, shutting it down for the duration")
+                (@arg name: +required "Name of the droplet")
+                (@arg size: +required "New size slug e.g. 1gb, s-2vcpu-2gb")
+                (@arg disk: --disk "Also grow the disk, which can't be undone (default: CPU and RAM only)")
+                (@arg yes: -y --yes "Don't ask for confirmation")
+            )
+            (@subcommand rebuild =>
+                (about: "Reinstall a droplet from an image, erasing its disk")
+                (@arg name: +required "Name of the droplet")
+                (@arg image: -i --image +takes_value "Which image? (default: image from config)")
+                (@arg yes: -y --yes "Don't ask for confirmation")
+            )
+            (@subcommand rename =>
+                (about: "Rename a droplet and move its domain records. Droplets tagged protected are refused.")
+                (@arg name: +required "Current name of the droplet")
+                (@arg new_name: +required "New full DNS name of the droplet")
+                (@arg domain: -d --domain +takes_value "Domain name (default: domain from config, else the account domain containing name)")
+            )
+            (@subcommand backups =>
+                (about: "Turn automatic backups on or off for an existing droplet")
+                (@arg name: +required "Name of the droplet")
+                (@arg state: +required possible_values(&["on", "off"]) "on or off")
             )
             (@subcommand create_sshkey =>
-                (about: "Add an ssh key, which will be added upon instance creation")
+                (about: "Add ~/.ssh/id_rsa.pub as an ssh key, which will be added upon instance creation")
                 (@arg name: +required "Name of the new ssh keys")
             )
+            (@subcommand sshkey =>
+                (about: "Manage the ssh keys on the account")
+                (@subcommand list =>
+                    (about: "List ssh keys as ID, fingerprint and name")
+                )
+                (@subcommand delete =>
+                    (about: "Delete an ssh key")
+                    (@arg key: +required "Name, ID or fingerprint of the key")
+                )
+                (@subcommand import =>
+                    (about: "Upload a public key, skipped if its fingerprint is already on the account")
+                    (@arg name: +required "Name of the new ssh key")
+                    (@arg path: -f --file +takes_value "Path to the public key (default: ~/.ssh/id_<type>.pub with --generate, else ~/.ssh/id_rsa.pub)")
+                    (@arg generate: -g --generate "Generate the keypair first if the private key doesn't exist")
+                    (@arg type: -t --type +takes_value possible_values(&["ed25519", "rsa", "ecdsa"]) "Key type to generate (default: ed25519)")
+                )
+            )
+        )
+        (@subcommand dns =>
+            (about: "Manage domain records, matched by exact name and type")
+            (@subcommand list =>
+                (about: "List records as ID, type, name, TTL and data")
+                (@arg name: "Only records with exactly this name")
+                (@arg type: -t --type +takes_value "Only records of this type")
+                (@arg domain: -d --domain +takes_value "Domain name (default: domain from config)")
+            )
+            (@subcommand create =>
+                (about: "Add a record, even if one with the same name and type exists")
+                (@arg name: +required "Record name relative to the domain, @ for the domain itself")
+                (@arg data: +required "Record data, e.g. an IP address or CNAME target")
+                (@arg type: -t --type +takes_value "Record type [A (default), AAAA, CNAME, TXT, MX, ...]")
+                (@arg ttl: --ttl +takes_value "TTL in seconds (default: 1800)")
+                (@arg domain: -d --domain +takes_value "Domain name (default: domain from config)")
+            )
+            (@subcommand update =>
+                (about: "Update the record with this name and type in place, creating it if missing")
+                (@arg name: +required "Record name relative to the domain, @ for the domain itself")
+                (@arg data: +required "Record data, e.g. an IP address or CNAME target")
+                (@arg type: -t --type +takes_value "Record type [A (default), AAAA, CNAME, TXT, MX, ...]")
+                (@arg ttl: --ttl +takes_value "TTL in seconds (default: unchanged)")
+                (@arg domain: -d --domain +takes_value "Domain name (default: domain from config)")
+            )
+            (@subcommand delete =>
+                (about: "Delete every record with exactly this name")
+                (@arg name: +required "Record name relative to the domain, @ for the domain itself")
+                (@arg type: -t --type +takes_value "Only delete records of this type")
+                (@arg domain: -d --domain +takes_value "Domain name (default: domain from config)")
+            )
+        )
+        (@subcommand snapshot =>
+            (about: "Manage droplet snapshots and backups")
+            (@subcommand list =>
+                (about: "List a droplet's snapshots, newest first")
+                (@arg name: +required "Name of the droplet")
+            )
+            (@subcommand backups =>
+                (about: "List a droplet's automatic backups, newest first")
+                (@arg name: +required "Name of the droplet")
+            )
+            (@subcommand create =>
+                (about: "Snapshot a droplet and wait for it to finish")
+                (@arg name: +required "Name of the droplet")
+                (@arg snapshot_name: -n --name +takes_value "Snapshot name (default: droplet name and timestamp)")
+            )
+            (@subcommand restore =>
+                (about: "Restore a droplet in place from one of its snapshots or backups")
+                (@arg name: +required "Name of the droplet")
+                (@arg snapshot: +required "Snapshot or backup name or ID")
+                (@arg yes: -y --yes "Don't ask for confirmation")
+            )
+            (@subcommand delete =>
+                (about: "Delete one of a droplet's snapshots")
+                (@arg name: +required "Name of the droplet")
+                (@arg snapshot: +required "Snapshot name or ID")
+                (@arg yes: -y --yes "Don't ask for confirmation")
+            )
+            (@subcommand prune =>
+                (about: "Delete all but the newest snapshots of each droplet, never prompts so it can run from cron")
+                (@arg keep: -k --keep +takes_value +required "How many snapshots to keep per droplet")
+                (@arg name: +multiple "Droplets to prune (default: every droplet)")
+                (@arg dry_run: -n --("dry-run") "Only print what would be deleted")
+            )
+        )
+        (@subcommand firewall =>
+            (about: "Manage DigitalOcean Cloud Firewalls, using the same ports as setup_iptables")
+            (@subcommand list =>
+                (about: "List cloud firewalls and what they're attached to")
+            )
+            (@subcommand apply =>
+                (about: "Create or update a cloud firewall allowing only 80,443,22 (or the given policy) in")
+                (@arg name: +required "Name of the cloud firewall")
+                (@arg droplet: --droplet +takes_value +multiple number_of_values(1) "Attach to this droplet, repeatable")
+                (@arg tag: --tag +takes_value +multiple number_of_values(1) "Attach to droplets with this tag, repeatable")
+                (args: &policy_arg_list(true))
+            )
+            (@subcommand delete =>
+                (about: "Delete a cloud firewall")
+                (@arg name: +required "Name of the cloud firewall")
+                (@arg yes: -y --yes "Don't ask for confirmation")
+            )
+        )
+        (@subcommand deploy =>
+            (about: "Sync a project to a host, build it and (re)start it behind the nginx vhost")
+            (@arg host: +required "Host name of the droplet")
+            (@arg path: "Local project directory, or repository with --ref (default: .)")
+            (@arg user: -u --user +takes_value "User to ssh in as (default: the user configure harden set up, else user from config, else root)")
+            (@arg git_ref: --ref +takes_value "Deploy this git commit, branch or tag instead of the working tree")
+            (@arg name: -n --name +takes_value "App name, used for the service and /srv/<name> (default: host)")
+            (@arg dir: --dir +takes_value "Directory on the host (default: /srv/<name>)")
+            (@arg port: --port +takes_value "Port the app listens on, passed as $PORT (default: the port the nginx vhost proxies to, else 8080)")
+            (@arg build: --build +takes_value conflicts_with[no_build] "Build command run on the host (default: cargo, npm or jekyll build, detected from the project)")
+            (@arg no_build: --("no-build") "Skip the build step")
+            (@arg start: --start +takes_value "Command that runs the app (default: detected from the project)")
+            (@setting SubcommandsNegateReqs)
+            (@subcommand compose =>
+                (about: "Upload a docker compose file to /srv/<name> and bring the stack up. When a service has build:, the file's whole directory goes up as the build context.")
+                (@arg host: +required "Host name of the droplet")
+                (@arg file: +required "Local compose file")
+                (@arg user: -u --user +takes_value "User to ssh in as (default: the user configure harden set up, else user from config, else root)")
+                (@arg name: -n --name +takes_value "App name, used for /srv/<name> and the compose project (default: host)")
+                (@arg env_file: --("env-file") +takes_value "Local env file, uploaded as the stack's .env")
+                (@arg service: --service +takes_value "Compose service for nginx to proxy to, published on localhost. Ports it publishes in the compose file stay published too.")
+                (@arg container_port: --("container-port") +takes_value requires[service] "Port the service listens on inside its container (default: 8080)")
+                (@arg port: --port +takes_value requires[service] "Host port to publish it on (default: the port the nginx vhost proxies to, else 8080)")
+            )
+        )
+        (@subcommand upgrade =>
+            (about: "Upgrade every package on an Ubuntu host now, reporting what changed and whether it needs a reboot")
+            (@arg host: +required "Host name of the droplet")
+            (@arg user: -u --user +takes_value "User to ssh in as (default: the user configure harden set up, else user from config, else root)")
+            (@arg reboot: --reboot "Reboot afterwards if an upgrade needs it")
         )
         (@subcommand configure =>
             (about: "Configure droplets / nodes")
+            (@arg user: -u --user +takes_value "User to ssh in as (default: the user configure harden set up, else user from config, else root)")
             (@subcommand nginx =>
                 (about: "Install nginx and configure for host")
                 (@arg host: +required "Host name of the droplet")
                 (@arg port: "Port to run webapp from (default: 8080)")
             )
             (@subcommand rust =>
-                (about: "Install rust on an ubuntu host")
+                (about: "Install rust with rustup, skipped if the toolchain is already set up")
                 (@arg host: +required "Host name of the droplet")
+                (@arg for_user: --("for-user") +takes_value "Install for this user (default: the ssh user)")
+                (@arg toolchain: --toolchain +takes_value "stable, beta, nightly or a version like 1.75.0 (default: stable)")
+                (@arg rustup_profile: --("rustup-profile") +takes_value possible_values(breezyvps::runtimes::RUSTUP_PROFILES) "rustup profile (default: rustup's default)")
+                (@arg component: --component +takes_value +multiple number_of_values(1) "Extra component e.g. clippy, rustfmt, repeatable")
+                (@arg target: --target +takes_value +multiple number_of_values(1) "Cross compilation target e.g. wasm32-unknown-unknown, repeatable")
             )
             (@subcommand python =>
-                (about: "Install python2.7 on an ubuntu host")
+                (about: "Install python3 with venv and pip")
                 (@arg host: +required "Host name of the droplet")
+                (@arg version: --version +takes_value "Python version e.g. 3.11, from deadsnakes on Ubuntu (default: the distro's python3)")
+                (@arg venv: --venv +takes_value "Also create a virtualenv at this path")
             )
             (@subcommand jekyll =>
-                (about: "Install jekyll on an ubuntu host")
+                (about: "Install jekyll")
                 (@arg host: +required "Host name of the droplet")
+                (@arg ruby_version: --("ruby-version") +takes_value "Build this ruby with rbenv for the ssh user (default: the distro's ruby)")
             )
             (@subcommand renew =>
-                (about: "Renew letsencrypt cert on ubuntu host")
+                (about: "Renew letsencrypt cert")
                 (@arg host: +required "Host name of the droplet")
             )
             (@subcommand setup_iptables =>
-                (about: "Setup iptables to only allow 80,443,22")
+                (about: "Setup a persistent IPv4 and IPv6 firewall to only allow 80,443,22, or the given policy")
+                (@arg host: +required "Host name of the droplet")
+                (@arg backend: --backend +takes_value possible_values(&["iptables", "nftables"]) "Firewall backend (default: nftables if the host has nft, else iptables)")
+                (@arg revert_after: --("revert-after") +takes_value "Restore the old rules after this many seconds unless we can reconnect, at least 10 (default: 60)")
+                (args: &policy_arg_list(true))
+            )
+            (@subcommand service =>
+                (about: "Install, enable and start a systemd unit for an app, then report its status")
+                (@arg host: +required "Host name of the droplet")
+                (@arg name: +required "Service name, the unit is breezyvps-<name>")
+                (@arg exec: -e --exec +takes_value +required "Command that runs the app, through sh")
+                (@arg run_as: --("run-as") +takes_value "User the app runs as (default: the ssh user)")
+                (@arg dir: --dir +takes_value "Working directory (default: /srv/<name>)")
+                (@arg env_file: --("env-file") +takes_value "Local env file, uploaded to /etc/breezyvps/<name>.env which the service always loads")
+                (@arg restart: --restart +takes_value possible_values(breezyvps::service::RESTART_POLICIES) "systemd restart policy (default: always)")
+                (@arg port: --port +takes_value "Port the app listens on, passed as $PORT")
+            )
+            (@subcommand harden =>
+                (about: "Create a sudo deploy user, turn off root and password ssh logins and optionally move sshd. Later commands connect as that user.")
+                (@arg host: +required "Host name of the droplet")
+                (@arg deploy_user: --("deploy-user") +takes_value "User to create (default: deploy)")
+                (@arg new_ssh_port: --("ssh-port") +takes_value value_name("ssh_port") "Move sshd to this port, along with its firewall rules")
+                (@arg firewall: --firewall "Apply the firewall policy even if the host has no firewall up yet")
+                (@arg backend: --backend +takes_value possible_values(&["iptables", "nftables"]) "Firewall backend (default: nftables if the host has nft, else iptables)")
+                (@arg revert_after: --("revert-after") +takes_value "Restore the old firewall rules after this many seconds unless we can reconnect, at least 10 (default: 60)")
+                (args: &policy_arg_list(false))
+            )
+            (@subcommand updates =>
+                (about: "Install security updates automatically with unattended-upgrades on Ubuntu")
+                (@arg host: +required "Host name of the droplet")
+                (@arg reboot_window: --("reboot-window") +takes_value "Run upgrades in this window, e.g. 03:00-05:00 host time, and reboot when one needs it (default: never reboot)")
+            )
+            (@subcommand postgres =>
+                (about: "Install PostgreSQL listening on localhost only, with a database and user, and print its connection string")
+                (@arg host: +required "Host name of the droplet")
+                (@arg database: --database +takes_value "Database to create (default: app)")
+                (@arg db_user: --("db-user") +takes_value "User owning the database, given a new random password (default: the database name)")
+                (@arg app: --app +takes_value "Also store DATABASE_URL in this service's /etc/breezyvps/<app>.env")
+            )
+            (@subcommand mysql =>
+                (about: "Install MariaDB listening on localhost only, with a database and user, and print its connection string")
+                (@arg host: +required "Host name of the droplet")
+                (@arg database: --database +takes_value "Database to create (default: app)")
+                (@arg db_user: --("db-user") +takes_value "User with all privileges on the database, given a new random password (default: the database name)")
+                (@arg app: --app +takes_value "Also store DATABASE_URL in this service's /etc/breezyvps/<app>.env")
+            )
+            (@subcommand redis =>
+                (about: "Install Redis listening on localhost only with a new random password, and print its connection string")
+                (@arg host: +required "Host name of the droplet")
+                (@arg app: --app +takes_value "Also store REDIS_URL in this service's /etc/breezyvps/<app>.env")
+            )
+            (@subcommand base =>
+                (about: "Set the hostname to the host's name, timezone, locale and time sync, and add a swap file")
+                (@arg host: +required "Host name of the droplet")
+                (@arg timezone: --timezone +takes_value "Timezone e.g. America/Los_Angeles (default: UTC)")
+                (@arg locale: --locale +takes_value "Locale (default: en_US.UTF-8)")
+                (@arg swap: --swap +takes_value "Swap file size e.g. 512M, 2G or 0 for none (default: 1G)")
+            )
+            (@subcommand docker =>
+                (about: "Install Docker Engine and the compose plugin from Docker's repository")
                 (@arg host: +required "Host name of the droplet")
             )
             (@subcommand sqlite3 =>
-                (about: "Install sqlite3 on Ubuntu")
+                (about: "Install sqlite3 and its headers")
                 (@arg host: +required "Host name of the droplet")
             )
             (@subcommand nodejs =>
-                (about: "Install nodejs on Ubuntu")
+                (about: "Install nodejs and npm")
                 (@arg host: +required "Host name of the droplet")
+                (@arg version: --version +takes_value "Release line e.g. 20 or lts from NodeSource, or an exact version e.g. 20.11.1 with nvm (default: the distro's nodejs)")
+                (@arg nvm: --nvm "Install with nvm for the ssh user, even for a release line")
             )
         )
     ).get_matches();
 
+    let config = match BreezyConfig::load(matches.value_of("profile")) {
+        Ok(config) => config,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+    config.export_token();
+
     // You can handle information about subcommands by requesting their matches by name
     // (as below), requesting just the name used, or both at the same time
     if let Some(matches) = matches.subcommand_matches("doctl") {
-        sc_doctl(matches);
+        sc_doctl(matches, &config);
+        return;
+    }
+    if let Some(matches) = matches.subcommand_matches("dns") {
+        sc_dns(matches, &config);
+        return;
+    }
+    if let Some(matches) = matches.subcommand_matches("snapshot") {
+        sc_snapshot(matches);
+        return;
+    }
+    if let Some(matches) = matches.subcommand_matches("firewall") {
+        sc_firewall(matches);
         return;
     }
     if let Some(matches) = matches.subcommand_matches("configure") {
-        sc_configure(matches);
+        sc_configure(matches, &config);
         return;
     }
+    if let Some(matches) = matches.subcommand_matches("deploy") {
+        sc_deploy(matches, &config);
+        return;
+    }
+    if let Some(matches) = matches.subcommand_matches("upgrade") {
+        sc_upgrade(matches, &config);
+    }
 }
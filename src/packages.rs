@@ -0,0 +1,147 @@
+use super::remote::{OsFamily, Remote};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PackageManager {
+    Apt,
+    Dnf,
+    Apk,
+}
+
+// What the configure installers ask for, mapped to each distro's package names
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Package {
+    Nginx,
+    CertbotNginx,
+    // sqlite3 along with its headers, for building against it
+    Sqlite3,
+    Nodejs,
+    Python3,
+    // ruby, gem and the headers native gems build against
+    RubyDev,
+    // C compiler, make and friends
+    BuildTools,
+    // Headers and tools ruby-build needs to compile ruby
+    RubyBuildDeps,
+    Curl,
+    PostgresServer,
+    // MariaDB, which every distro ships in MySQL's place
+    MysqlServer,
+    RedisServer,
+    Tzdata,
+    Chrony,
+}
+
+impl PackageManager {
+    pub fn for_family(family: OsFamily) -> Option<PackageManager> {
+        match family {
+            OsFamily::Debian => Some(PackageManager::Apt),
+            OsFamily::Fedora => Some(PackageManager::Dnf),
+            OsFamily::Alpine => Some(PackageManager::Apk),
+            OsFamily::Unknown => None,
+        }
+    }
+
+    // From /etc/os-release, else whichever package manager the host has
+    pub fn detect(remote: &Remote) -> Result<PackageManager, String> {
+        if let Some(manager) = PackageManager::for_family(remote.os_family()) {
+            return Ok(manager);
+        }
+        let probes = [("apt-get", PackageManager::Apt), ("dnf", PackageManager::Dnf), ("apk", PackageManager::Apk)];
+        probes.iter()
+            .find(|&&(binary, _)| remote.run(&format!("command -v {}", binary)).success)
+            .map(|&(_, manager)| manager)
+            .ok_or_else(|| format!("Couldn't find apt-get, dnf or apk on {}", remote.host))
+    }
+
+    // Refreshes the package index and installs packages, run as root
+    pub fn install_command(&self, packages: &[Package]) -> String {
+        let mut names: Vec<&str> = packages.iter().flat_map(|p| p.names(*self).iter().cloned()).collect();
+        names.dedup();
+        match *self {
+            PackageManager::Apt => format!("apt-get update && DEBIAN_FRONTEND=noninteractive apt-get install -y {}", names.join(" ")),
+            PackageManager::Dnf => format!("dnf install -y {}", names.join(" ")),
+            PackageManager::Apk => format!("apk update && apk add {}", names.join(" ")),
+        }
+    }
+}
+
+impl Package {
+    pub fn names(&self, manager: PackageManager) -> &'static [&'static str] {
+        use self::PackageManager::*;
+        match (*self, manager) {
+            (Package::Nginx, _) => &["nginx"],
+            (Package::CertbotNginx, Apk) => &["certbot", "certbot-nginx"],
+            (Package::CertbotNginx, _) => &["certbot", "python3-certbot-nginx"],
+            (Package::Sqlite3, Apt) => &["sqlite3", "libsqlite3-dev"],
+            (Package::Sqlite3, Dnf) => &["sqlite", "sqlite-devel"],
+            (Package::Sqlite3, Apk) => &["sqlite", "sqlite-dev"],
+            (Package::Nodejs, _) => &["nodejs", "npm"],
+            (Package::Python3, Apt) => &["python3", "python3-pip", "python3-venv"],
+            (Package::Python3, Dnf) => &["python3", "python3-pip"],
+            (Package::Python3, Apk) => &["python3", "py3-pip"],
+            (Package::RubyDev, Apt) => &["ruby", "ruby-dev"],
+            (Package::RubyDev, Dnf) => &["ruby", "ruby-devel", "rubygems"],
+            (Package::RubyDev, Apk) => &["ruby", "ruby-dev"],
+            (Package::BuildTools, Apt) => &["build-essential"],
+            (Package::BuildTools, Dnf) => &["gcc", "gcc-c++", "make"],
+            (Package::BuildTools, Apk) => &["build-base"],
+            (Package::RubyBuildDeps, Apt) => &["git", "libssl-dev", "libyaml-dev", "zlib1g-dev", "libffi-dev", "libreadline-dev"],
+            (Package::RubyBuildDeps, Dnf) => &["git", "perl", "openssl-devel", "libyaml-devel", "zlib-devel", "libffi-devel", "readline-devel"],
+            (Package::RubyBuildDeps, Apk) => &["git", "openssl-dev", "yaml-dev", "zlib-dev", "libffi-dev", "readline-dev", "linux-headers"],
+            (Package::Curl, _) => &["curl"],
+            (Package::PostgresServer, Apt) => &["postgresql"],
+            (Package::PostgresServer, Dnf) => &["postgresql-server"],
+            (Package::PostgresServer, Apk) => &["postgresql", "postgresql-client"],
+            (Package::MysqlServer, Apk) => &["mariadb", "mariadb-client"],
+            (Package::MysqlServer, _) => &["mariadb-server"],
+            (Package::RedisServer, Apt) => &["redis-server"],
+            (Package::RedisServer, _) => &["redis"],
+            (Package::Tzdata, _) => &["tzdata"],
+            (Package::Chrony, _) => &["chrony"],
+        }
+    }
+}
+
+// Installs packages with whatever package manager the host uses
+pub fn install(remote: &Remote, packages: &[Package]) -> Result<(), String> {
+    let manager = PackageManager::detect(remote)?;
+    info!("Installing {:?} on {} with {:?}", packages, remote.host, manager);
+    let result = remote.run_root(&manager.install_command(packages));
+    if !result.success {
+        return Err(format!("Failed to install {:?} on {}:\n\n{}", packages, remote.host, result.stderr));
+    }
+    Ok(())
+}
+
+// Enables and starts a service if it isn't already running, with systemd or OpenRC.
+// Parenthesised so it can be chained with &&.
+pub fn enable_service_script(name: &str) -> String {
+    format!("(systemctl enable --now {0} || (rc-update add {0} default && rc-service {0} start))", name)
+}
+
+// Enables and restarts a service, with systemd or OpenRC
+pub fn restart_service_script(name: &str) -> String {
+    format!("(systemctl enable {0} && systemctl restart {0} || (rc-update add {0} default && rc-service {0} restart))", name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_logical_packages_per_distro() {
+        let packages = [Package::Sqlite3, Package::BuildTools];
+        assert_eq!(PackageManager::Apt.install_command(&packages),
+                   "apt-get update && DEBIAN_FRONTEND=noninteractive apt-get install -y sqlite3 libsqlite3-dev build-essential");
+        assert_eq!(PackageManager::Dnf.install_command(&packages), "dnf install -y sqlite sqlite-devel gcc gcc-c++ make");
+        assert_eq!(PackageManager::Apk.install_command(&packages), "apk update && apk add sqlite sqlite-dev build-base");
+    }
+
+    #[test]
+    fn picks_the_manager_for_each_family() {
+        assert_eq!(PackageManager::for_family(OsFamily::Debian), Some(PackageManager::Apt));
+        assert_eq!(PackageManager::for_family(OsFamily::Fedora), Some(PackageManager::Dnf));
+        assert_eq!(PackageManager::for_family(OsFamily::Alpine), Some(PackageManager::Apk));
+        assert_eq!(PackageManager::for_family(OsFamily::Unknown), None);
+    }
+}
@@ -0,0 +1,66 @@
+use std::net::{TcpStream, ToSocketAddrs};
+use std::thread;
+use std::time::{Duration, Instant};
+use super::command;
+use super::known_hosts;
+use super::remote::Remote;
+
+const RETRY_DELAY: Duration = Duration::from_secs(3);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn wait_for_port(host: &str, port: u16, deadline: Instant) -> Result<(), String> {
+    loop {
+        let connected = (host, port).to_socket_addrs()
+            .map(|mut addrs| addrs.any(|addr| TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT).is_ok()))
+            .unwrap_or(false);
+        if connected {
+            return Ok(());
+        }
+        if Instant::now() >= deadline {
+            return Err(format!("Gave up waiting for port {} on {}", port, host));
+        }
+        thread::sleep(RETRY_DELAY);
+    }
+}
+
+// sshd can accept connections before it has generated its host keys, so retry until it offers some
+fn scan_host_keys(host: &str, port: u16, deadline: Instant) -> Result<String, String> {
+    loop {
+        let scan = known_hosts::scan(host, port);
+        if !scan.is_empty() {
+            return Ok(scan);
+        }
+        if Instant::now() >= deadline {
+            return Err(format!("Gave up waiting for host keys from {}", host));
+        }
+        thread::sleep(RETRY_DELAY);
+    }
+}
+
+// Waits until a freshly created host takes ssh logins: its port is open, its host keys are in
+// breezyvps' known_hosts under every name in aliases as well as remote.host, and a trivial command runs.
+// ~/.ssh/known_hosts is left alone.
+pub fn wait_for_ssh(remote: &Remote, aliases: &[&str], timeout: Duration) -> Result<(), String> {
+    let remote = &remote.clone().with_known_hosts(remote.known_hosts.clone().or_else(known_hosts::managed_file));
+    let deadline = Instant::now() + timeout;
+    let port = remote.port.unwrap_or(22);
+    info!("Waiting for ssh on {}:{}", remote.host, port);
+    wait_for_port(&remote.host, port, deadline)?;
+    let scan = scan_host_keys(&remote.host, port, deadline)?;
+    let mut names = vec![remote.host.as_str()];
+    names.extend(aliases.iter().cloned());
+    known_hosts::record(&scan, &names, port)?;
+
+    // Logins can still be refused while cloud-init sets up authorized_keys
+    loop {
+        let result = command::run_host_cmd(&remote.ssh_fresh(5, "true"));
+        if result.success {
+            println!("{} is accepting ssh logins", remote.host);
+            return Ok(());
+        }
+        if Instant::now() >= deadline {
+            return Err(format!("Gave up waiting to log in to {}: {}", remote.target(), result.stderr.trim()));
+        }
+        thread::sleep(RETRY_DELAY);
+    }
+}
@@ -0,0 +1,214 @@
+use super::command;
+use super::command::shell_quote;
+
+// Which flavour of distro a host runs, enough to pick package names and service managers
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OsFamily {
+    Debian,
+    Fedora,
+    Alpine,
+    Unknown,
+}
+
+// Reads ID and ID_LIKE out of /etc/os-release
+pub fn parse_os_release(contents: &str) -> OsFamily {
+    let ids: Vec<String> = contents.lines()
+        .filter(|line| line.starts_with("ID=") || line.starts_with("ID_LIKE="))
+        .flat_map(|line| {
+            let value = line.split_once('=').map(|(_, v)| v).unwrap_or("");
+            value.trim_matches('"').split_whitespace().map(|id| id.to_lowercase()).collect::<Vec<_>>()
+        })
+        .collect();
+    let has = |id: &str| ids.iter().any(|i| i == id);
+    if has("debian") || has("ubuntu") {
+        OsFamily::Debian
+    } else if has("fedora") || has("rhel") || has("centos") {
+        OsFamily::Fedora
+    } else if has("alpine") {
+        OsFamily::Alpine
+    } else {
+        OsFamily::Unknown
+    }
+}
+
+// A host we reach over ssh, along with the user to log in as. Users other than root are
+// expected to have passwordless sudo, which the *_root methods use.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Remote {
+    pub user: String,
+    pub host: String,
+    // sshd port, ssh's default when unset
+    pub port: Option<u16>,
+    // known_hosts file consulted before ~/.ssh/known_hosts. Unknown host keys are still refused,
+    // keys only get in there through known_hosts::record.
+    pub known_hosts: Option<String>,
+}
+
+impl Remote {
+    pub fn new(user: &str, host: &str) -> Self {
+        Remote {
+            user: user.to_string(),
+            host: host.to_string(),
+            port: None,
+            known_hosts: None,
+        }
+    }
+
+    pub fn with_port(mut self, port: Option<u16>) -> Self {
+        self.port = port;
+        self
+    }
+
+    pub fn with_known_hosts(mut self, known_hosts: Option<String>) -> Self {
+        self.known_hosts = known_hosts;
+        self
+    }
+
+    // Options ssh and scp share, each with a leading space
+    fn known_hosts_options(&self) -> String {
+        match self.known_hosts {
+            Some(ref file) => format!(" -o {}", shell_quote(&format!("UserKnownHostsFile={} ~/.ssh/known_hosts", file))),
+            None => String::new(),
+        }
+    }
+
+    pub fn target(&self) -> String {
+        format!("{}@{}", self.user, self.host)
+    }
+
+    // Host command which runs cmd on the remote
+    pub fn ssh(&self, cmd: &str) -> String {
+        self.ssh_with("", cmd)
+    }
+
+    // Same as ssh, with extra ssh options
+    pub fn ssh_with(&self, options: &str, cmd: &str) -> String {
+        let options = if options.is_empty() { String::new() } else { format!("{} ", options) };
+        format!("{} {}{} {}", self.ssh_transport(), options, self.target(), shell_quote(cmd))
+    }
+
+    // The ssh command line tools like rsync should connect with
+    pub fn ssh_transport(&self) -> String {
+        match self.port {
+            Some(port) => format!("ssh -p {}{}", port, self.known_hosts_options()),
+            None => format!("ssh{}", self.known_hosts_options()),
+        }
+    }
+
+    // cmd wrapped so it runs as root whoever we log in as
+    pub fn sudo(&self, cmd: &str) -> String {
+        if self.user == "root" {
+            cmd.to_string()
+        } else {
+            format!("sudo -n sh -c {}", shell_quote(cmd))
+        }
+    }
+
+    // Host command which runs cmd on the remote as root
+    pub fn ssh_root(&self, cmd: &str) -> String {
+        self.ssh(&self.sudo(cmd))
+    }
+
+    // cmd wrapped so it runs as user, in a login environment with user's HOME
+    pub fn as_user(&self, user: &str, cmd: &str) -> String {
+        if user == self.user {
+            cmd.to_string()
+        } else if self.user == "root" {
+            format!("su - {} -s /bin/sh -c {}", shell_quote(user), shell_quote(cmd))
+        } else {
+            format!("sudo -n -u {} -H sh -c {}", shell_quote(user), shell_quote(cmd))
+        }
+    }
+
+    // Same as ssh, but always on a new connection which gives up quickly rather than prompting
+    pub fn ssh_fresh(&self, connect_timeout: u32, cmd: &str) -> String {
+        self.ssh_with(&format!("-o ControlPath=none -o BatchMode=yes -o ConnectTimeout={}", connect_timeout), cmd)
+    }
+
+    // Runs cmd on the remote right away
+    pub fn run(&self, cmd: &str) -> command::Result {
+        command::run_host_cmd(&self.ssh(cmd))
+    }
+
+    // Runs cmd on the remote as root right away
+    pub fn run_root(&self, cmd: &str) -> command::Result {
+        command::run_host_cmd(&self.ssh_root(cmd))
+    }
+
+    // Runs cmd on the remote as root with input on its stdin, for anything secret
+    pub fn run_root_with_input(&self, cmd: &str, input: &str) -> command::Result {
+        command::run_host_cmd_with_input(&self.ssh_root(cmd), input)
+    }
+
+    pub fn os_family(&self) -> OsFamily {
+        let result = self.run("cat /etc/os-release");
+        if !result.success {
+            warn!("Couldn't read /etc/os-release on {}: {}", self.host, result.stderr);
+            return OsFamily::Unknown;
+        }
+        parse_os_release(&result.stdout)
+    }
+
+    // Host command which copies a local file to remote_path
+    pub fn scp_to(&self, local_path: &str, remote_path: &str) -> String {
+        let port = self.port.map(|p| format!(" -P {}", p)).unwrap_or_default();
+        format!("scp{}{} {} {}:{}", port, self.known_hosts_options(), local_path, self.target(), remote_path)
+    }
+
+    // Same as scp_to, for paths only root can write, installed owned by root with mode. The file
+    // goes over ssh's stdin into a private mktemp -d directory, so it's never readable by other
+    // users on the host nor at a path they could plant, and then into place with sudo.
+    pub fn scp_to_root(&self, local_path: &str, remote_path: &str, mode: &str) -> String {
+        let filename = local_path.trim_matches('\'').rsplit('/').next().unwrap_or(local_path);
+        let staged = format!("\"$staging\"/{}", shell_quote(filename));
+        let sudo = if self.user == "root" { "" } else { "sudo -n " };
+        let script = format!("staging=$(mktemp -d) && (umask 077 && cat > {0} && {1}install -m {2} -o root {0} {3}); \
+                              status=$?; rm -rf \"$staging\"; exit $status", staged, sudo, mode, remote_path);
+        format!("{} < {}", self.ssh(&script), local_path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_os_family() {
+        assert_eq!(parse_os_release("NAME=\"Ubuntu\"\nID=ubuntu\nID_LIKE=debian\n"), OsFamily::Debian);
+        assert_eq!(parse_os_release("ID=\"centos\"\nID_LIKE=\"rhel fedora\"\n"), OsFamily::Fedora);
+        assert_eq!(parse_os_release("ID=alpine\n"), OsFamily::Alpine);
+        assert_eq!(parse_os_release("ID=arch\n"), OsFamily::Unknown);
+    }
+
+    #[test]
+    fn ssh_quotes_the_remote_command() {
+        let remote = Remote::new("root", "cloud.example.com");
+        assert_eq!(remote.ssh("echo hi"), "ssh root@cloud.example.com 'echo hi'");
+        assert_eq!(remote.ssh("echo 'hi'"), "ssh root@cloud.example.com 'echo '\\''hi'\\'''");
+    }
+
+    #[test]
+    fn non_root_users_go_through_sudo_on_their_port() {
+        let remote = Remote::new("deploy", "cloud.example.com").with_port(Some(2222));
+        assert_eq!(remote.sudo("systemctl restart nginx"), "sudo -n sh -c 'systemctl restart nginx'");
+        assert!(remote.ssh("true").starts_with("ssh -p 2222 deploy@cloud.example.com "));
+        assert_eq!(remote.scp_to("/tmp/site.conf", "/srv/"), "scp -P 2222 /tmp/site.conf deploy@cloud.example.com:/srv/");
+        let upload = remote.scp_to_root("/tmp/site.conf", "/etc/nginx/conf.d/", "644");
+        assert!(upload.starts_with("ssh -p 2222 deploy@cloud.example.com 'staging=$(mktemp -d) && (umask 077 && cat > \"$staging\"/"));
+        assert!(upload.contains("&& sudo -n install -m 644 -o root \"$staging\"/'\\''site.conf'\\'' /etc/nginx/conf.d/);"));
+        assert!(upload.ends_with("' < /tmp/site.conf"));
+        assert!(!upload.contains("/tmp/site.conf deploy@"));
+        assert_eq!(Remote::new("root", "cloud.example.com").sudo("true"), "true");
+        assert_eq!(remote.as_user("app", "cargo build"), "sudo -n -u 'app' -H sh -c 'cargo build'");
+        assert_eq!(Remote::new("root", "cloud.example.com").as_user("app", "cargo build"), "su - 'app' -s /bin/sh -c 'cargo build'");
+        assert_eq!(remote.as_user("deploy", "cargo build"), "cargo build");
+    }
+
+    #[test]
+    fn checks_our_known_hosts_first() {
+        let remote = Remote::new("root", "cloud.example.com").with_known_hosts(Some("/home/me/.config/breezyvps/known_hosts".to_string()));
+        let options = "-o 'UserKnownHostsFile=/home/me/.config/breezyvps/known_hosts ~/.ssh/known_hosts'";
+        assert_eq!(remote.ssh_transport(), format!("ssh {}", options));
+        assert_eq!(remote.scp_to("/tmp/a", "/tmp/"), format!("scp {} /tmp/a root@cloud.example.com:/tmp/", options));
+    }
+}
@@ -0,0 +1,254 @@
+use super::command::shell_quote;
+use super::harden;
+use super::packages::PackageManager;
+
+// Pinned so installs are repeatable
+const NVM_VERSION: &str = "v0.39.7";
+
+// Puts nvm on the PATH of a non-interactive shell
+pub const NVM_ENV: &str = ". \"$HOME/.nvm/nvm.sh\"";
+
+// Puts rustup and cargo on the PATH of a non-interactive shell
+pub const CARGO_ENV: &str = ". \"$HOME/.cargo/env\"";
+
+// Puts rbenv's ruby on the PATH of a non-interactive shell
+pub const RBENV_ENV: &str = "export PATH=\"$HOME/.rbenv/bin:$PATH\" && eval \"$(rbenv init - sh)\"";
+
+// Versions end up in shell commands, so only allow what version strings actually use
+pub fn validate_version(version: &str) -> Result<(), String> {
+    if !version.is_empty() && version.chars().all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_') {
+        Ok(())
+    } else {
+        Err(format!("Invalid version: {}", version))
+    }
+}
+
+// Whether the first version number in output, e.g. "v20.11.1" or "Python 3.11.4", is wanted
+// or a release of it, so "20" matches 20.11.1 but not 2.0 or 200.1
+pub fn version_matches(output: &str, wanted: &str) -> bool {
+    let found = output.split_whitespace()
+        .map(|word| word.trim_start_matches('v'))
+        .find(|word| word.starts_with(|c: char| c.is_ascii_digit()))
+        .map(|word| word.split(|c: char| !(c.is_ascii_digit() || c == '.')).next().unwrap_or(""))
+        .unwrap_or("");
+    found == wanted || found.starts_with(&format!("{}.", wanted))
+}
+
+// Only numbers can be checked against what ends up installed, not aliases like lts or stable
+pub fn is_numeric_version(version: &str) -> bool {
+    version.chars().all(|c| c.is_ascii_digit() || c == '.')
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum NodeInstall {
+    // Whatever the distro ships
+    System,
+    // A major release line, or lts/current, from NodeSource's repositories
+    NodeSource(String),
+    // An exact version through nvm, for the ssh user only
+    Nvm(String),
+}
+
+// Exact versions like 20.11.1 need nvm, NodeSource only tracks release lines
+pub fn node_install(version: Option<&str>, nvm: bool) -> Result<NodeInstall, String> {
+    match version {
+        None if nvm => Ok(NodeInstall::Nvm("lts/*".to_string())),
+        None => Ok(NodeInstall::System),
+        Some(version) => {
+            validate_version(version)?;
+            if nvm || version.contains('.') {
+                Ok(NodeInstall::Nvm(version.to_string()))
+            } else {
+                Ok(NodeInstall::NodeSource(version.to_string()))
+            }
+        },
+    }
+}
+
+// Run as root: adds NodeSource's repository for a release line and installs node from it
+pub fn nodesource_command(manager: PackageManager, line: &str) -> Result<String, String> {
+    match manager {
+        PackageManager::Apt => Ok(format!("curl -fsSL https://deb.nodesource.com/setup_{}.x | bash - && apt-get install -y nodejs", line)),
+        PackageManager::Dnf => Ok(format!("curl -fsSL https://rpm.nodesource.com/setup_{}.x | bash - && dnf install -y nodejs", line)),
+        PackageManager::Apk => Err("NodeSource doesn't build for Alpine, leave out --version to use Alpine's nodejs".to_string()),
+    }
+}
+
+// Run as the ssh user: installs nvm if needed, then version as the default node
+pub fn nvm_script(version: &str) -> String {
+    format!("([ -s \"$HOME/.nvm/nvm.sh\" ] || curl -fsSL https://raw.githubusercontent.com/nvm-sh/nvm/{nvm}/install.sh | bash) && \
+             {env} && nvm install {version} && nvm alias default {version}", nvm = NVM_VERSION, env = NVM_ENV, version = shell_quote(version))
+}
+
+// Root commands installing python3 version with venv and pip, the distro's own when None
+pub fn python_command(manager: PackageManager, version: Option<&str>) -> Result<Option<String>, String> {
+    let version = match version {
+        None => return Ok(None),
+        Some(version) => version,
+    };
+    validate_version(version)?;
+    match manager {
+        // Ubuntu only ships one python3 per release, deadsnakes has the rest
+        PackageManager::Apt => {
+            let install = format!("DEBIAN_FRONTEND=noninteractive apt-get install -y python{0} python{0}-venv python{0}-dev", version);
+            Ok(Some(format!("apt-get update && ({0} || (apt-get install -y software-properties-common && \
+                             add-apt-repository -y ppa:deadsnakes/ppa && apt-get update && {0}))", install)))
+        },
+        PackageManager::Dnf => Ok(Some(format!("dnf install -y python{0} python{0}-devel && python{0} -m ensurepip", version))),
+        PackageManager::Apk => Err("Alpine only ships its own python3, leave out --version".to_string()),
+    }
+}
+
+// The python binary for version
+pub fn python_binary(version: Option<&str>) -> String {
+    format!("python{}", version.unwrap_or("3"))
+}
+
+// Quoted for the remote shell, with a leading ~/ still meaning the ssh user's home
+fn remote_path(path: &str) -> String {
+    match path.strip_prefix("~/") {
+        Some(rest) => format!("\"$HOME\"/{}", shell_quote(rest)),
+        None => shell_quote(path),
+    }
+}
+
+// Run as the ssh user: a virtualenv at path with an up to date pip
+pub fn venv_script(python: &str, path: &str) -> String {
+    let path = remote_path(path);
+    format!("{0} -m venv {1} && {1}/bin/pip install --upgrade pip", python, path)
+}
+
+// rustup's install profiles
+pub const RUSTUP_PROFILES: &[&str] = &["minimal", "default", "complete"];
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RustOptions<'a> {
+    // Who rust is installed for, default the ssh user
+    pub user: Option<&'a str>,
+    // stable, beta, nightly, a version like 1.75.0 or a dated nightly, default stable
+    pub toolchain: Option<&'a str>,
+    // rustup profile, default rustup's own default
+    pub profile: Option<&'a str>,
+    // e.g. clippy, rustfmt
+    pub components: Vec<&'a str>,
+    // Cross compilation targets e.g. wasm32-unknown-unknown
+    pub targets: Vec<&'a str>,
+}
+
+impl<'a> RustOptions<'a> {
+    pub fn toolchain(&self) -> &'a str {
+        self.toolchain.unwrap_or("stable")
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        validate_version(self.toolchain())?;
+        if let Some(user) = self.user {
+            harden::validate_user(user)?;
+        }
+        if let Some(profile) = self.profile {
+            if !RUSTUP_PROFILES.contains(&profile) {
+                return Err(format!("Unknown rustup profile: {}", profile));
+            }
+        }
+        for name in self.components.iter().chain(self.targets.iter()) {
+            validate_version(name)?;
+        }
+        Ok(())
+    }
+
+    // Run as the user rust is for: succeeds only if the toolchain is already the default with
+    // every component and target installed
+    pub fn installed_check(&self) -> String {
+        let toolchain = self.toolchain();
+        let mut checks = vec![
+            format!("[ -x \"$HOME/.cargo/bin/rustup\" ] && {}", CARGO_ENV),
+            format!("rustup default | grep -q '^{}-'", toolchain),
+        ];
+        for component in self.components.iter() {
+            checks.push(format!("rustup component list --toolchain {} --installed | grep -qE '^{}(-| |$)'", toolchain, component));
+        }
+        for target in self.targets.iter() {
+            checks.push(format!("rustup target list --toolchain {} --installed | grep -qx '{}'", toolchain, target));
+        }
+        checks.join(" && ")
+    }
+
+    // Run as the user rust is for: rustup if it's missing, then the toolchain with its
+    // components and targets as the default
+    pub fn install_script(&self) -> String {
+        let profile = self.profile.map(|p| format!(" --profile {}", p)).unwrap_or_default();
+        let mut install = format!("rustup toolchain install {}{}", self.toolchain(), profile);
+        for component in self.components.iter() {
+            install.push_str(&format!(" -c {}", component));
+        }
+        for target in self.targets.iter() {
+            install.push_str(&format!(" -t {}", target));
+        }
+        format!("([ -x \"$HOME/.cargo/bin/rustup\" ] || curl --proto '=https' --tlsv1.2 -sSf https://sh.rustup.rs | sh -s -- -y --default-toolchain none{}) && \
+                 {} && {} && rustup default {}", profile, CARGO_ENV, install, self.toolchain())
+    }
+}
+
+// Run as the ssh user: rbenv and ruby-build if needed, then version as the global ruby, with
+// rbenv set up for login shells
+pub fn rbenv_script(version: &str) -> String {
+    format!("([ -d \"$HOME/.rbenv\" ] || git clone https://github.com/rbenv/rbenv.git \"$HOME/.rbenv\") && \
+             ([ -d \"$HOME/.rbenv/plugins/ruby-build\" ] || git clone https://github.com/rbenv/ruby-build.git \"$HOME/.rbenv/plugins/ruby-build\") && \
+             git -C \"$HOME/.rbenv/plugins/ruby-build\" pull -q && \
+             (grep -q 'rbenv init' \"$HOME/.profile\" 2>/dev/null || echo '{env}' >> \"$HOME/.profile\") && \
+             {env} && rbenv install -s {version} && rbenv global {version}", env = RBENV_ENV, version = version)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_versions_by_prefix() {
+        assert!(version_matches("v20.11.1\n", "20"));
+        assert!(version_matches("v20.11.1\n", "20.11.1"));
+        assert!(!version_matches("v2.0.1\n", "20"));
+        assert!(!version_matches("v200.1.0\n", "20"));
+        assert!(version_matches("Python 3.11.4", "3.11"));
+        assert!(version_matches("ruby 3.2.2 (2023-03-30 revision e51014f9c0) [x86_64-linux]", "3.2.2"));
+        assert!(version_matches("rustc 1.75.0 (82e1608df 2023-12-21)", "1.75.0"));
+        assert!(!version_matches("", "3"));
+    }
+
+    #[test]
+    fn picks_node_installs() {
+        assert_eq!(node_install(None, false), Ok(NodeInstall::System));
+        assert_eq!(node_install(Some("20"), false), Ok(NodeInstall::NodeSource("20".to_string())));
+        assert_eq!(node_install(Some("lts"), false), Ok(NodeInstall::NodeSource("lts".to_string())));
+        assert_eq!(node_install(Some("20.11.1"), false), Ok(NodeInstall::Nvm("20.11.1".to_string())));
+        assert_eq!(node_install(Some("20"), true), Ok(NodeInstall::Nvm("20".to_string())));
+        assert!(node_install(Some("20; rm -rf /"), false).is_err());
+        assert!(nodesource_command(PackageManager::Apk, "20").is_err());
+        assert!(nvm_script("20.11.1").ends_with("nvm install '20.11.1' && nvm alias default '20.11.1'"));
+    }
+
+    #[test]
+    fn quotes_venv_paths() {
+        assert_eq!(venv_script("python3", "~/my env"), "python3 -m venv \"$HOME\"/'my env' && \"$HOME\"/'my env'/bin/pip install --upgrade pip");
+        assert_eq!(venv_script("python3", "/srv/app;x"), "python3 -m venv '/srv/app;x' && '/srv/app;x'/bin/pip install --upgrade pip");
+    }
+
+    #[test]
+    fn builds_rustup_commands() {
+        let options = RustOptions {
+            toolchain: Some("1.75.0"),
+            profile: Some("minimal"),
+            components: vec!["clippy", "rust-src"],
+            targets: vec!["wasm32-unknown-unknown"],
+            ..RustOptions::default()
+        };
+        assert!(options.install_script()
+                .ends_with("rustup toolchain install 1.75.0 --profile minimal -c clippy -c rust-src -t wasm32-unknown-unknown && rustup default 1.75.0"));
+        assert!(options.installed_check().contains("rustup default | grep -q '^1.75.0-'"));
+        assert!(options.installed_check().ends_with("rustup target list --toolchain 1.75.0 --installed | grep -qx 'wasm32-unknown-unknown'"));
+        assert!(options.installed_check().contains("rustup component list --toolchain 1.75.0 --installed | grep -qE '^clippy(-| |$)'"));
+        assert!(RustOptions { profile: Some("tiny"), ..RustOptions::default() }.validate().is_err());
+        assert!(RustOptions { user: Some("app; rm -rf ~"), ..RustOptions::default() }.validate().is_err());
+        assert!(RustOptions::default().install_script().ends_with("rustup toolchain install stable && rustup default stable"));
+    }
+}
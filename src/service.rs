@@ -0,0 +1,154 @@
+use super::chain;
+use super::command::shell_quote;
+use super::configure;
+use super::remote::Remote;
+
+// Restart= values we accept
+pub const RESTART_POLICIES: &[&str] = &["always", "on-failure", "on-abnormal", "no"];
+
+// Where uploaded env files live on the host, one per service
+const ENV_DIR: &str = "/etc/breezyvps";
+
+// A long running app managed by systemd as breezyvps-<name>
+#[derive(Clone, Debug, PartialEq)]
+pub struct ServiceUnit {
+    pub name: String,
+    // Run through sh, so PATH lookups, $VARS and && work as they would over ssh
+    pub exec: String,
+    pub user: String,
+    pub working_dir: String,
+    // Path of the env file on the host, loaded only if it exists
+    pub env_file: Option<String>,
+    pub restart: String,
+    // Passed to the app as $PORT
+    pub port: Option<String>,
+}
+
+pub fn unit_name(name: &str) -> String {
+    format!("breezyvps-{}", name)
+}
+
+pub fn env_file_path(name: &str) -> String {
+    format!("{}/{}.env", ENV_DIR, name)
+}
+
+impl ServiceUnit {
+    // Runs exec from /srv/<name> as user with /etc/breezyvps/<name>.env, restarting whenever it exits
+    pub fn new(name: &str, exec: &str, user: &str) -> Self {
+        ServiceUnit {
+            name: name.to_string(),
+            exec: exec.to_string(),
+            user: user.to_string(),
+            working_dir: format!("/srv/{}", name),
+            env_file: Some(env_file_path(name)),
+            restart: "always".to_string(),
+            port: None,
+        }
+    }
+
+    pub fn unit_name(&self) -> String {
+        unit_name(&self.name)
+    }
+
+    pub fn render(&self) -> String {
+        let mut service = String::new();
+        if self.user != "root" {
+            service.push_str(&format!("User={}\n", self.user));
+        }
+        service.push_str(&format!("WorkingDirectory={}\n", self.working_dir));
+        if let Some(ref port) = self.port {
+            service.push_str(&format!("Environment=PORT={}\n", port));
+        }
+        if let Some(ref env_file) = self.env_file {
+            service.push_str(&format!("EnvironmentFile=-{}\n", env_file));
+        }
+        // systemd expands $VAR itself, $$ hands a literal $ through to the shell
+        service.push_str(&format!("ExecStart=/bin/sh -lc {}\n", shell_quote(&self.exec.replace('$', "$$"))));
+        service.push_str(&format!("Restart={}\n", self.restart));
+        format!("[Unit]\nDescription={} (managed by breezyvps)\nAfter=network.target\n\n[Service]\n{}\n[Install]\nWantedBy=multi-user.target\n",
+                self.name, service)
+    }
+}
+
+// Uploads the unit, and local_env_file to unit.env_file if given, then enables and (re)starts it
+pub fn install(remote: &Remote, unit: &ServiceUnit, local_env_file: Option<&str>) -> Result<(), String> {
+    let unit_file = configure::write_local_file(&format!("{}.service", unit.unit_name()), &unit.render());
+    let mut chain = chain::CommandChain::new();
+    if let (Some(local), Some(ref env_file)) = (local_env_file, unit.env_file.as_ref()) {
+        chain = chain
+            .cmd(&remote.ssh_root(&format!("mkdir -p {} && touch {1} && chmod 600 {1}", ENV_DIR, env_file)))
+            .cmd(&remote.scp_to_root(local, env_file, "600"));
+    }
+    let res = chain
+        .cmd(&remote.scp_to_root(&unit_file, &format!("/etc/systemd/system/{}.service", unit.unit_name()), "644"))
+        .cmd(&format!("rm {}", unit_file))
+        .cmd(&remote.ssh_root(&format!("systemctl daemon-reload && systemctl enable {0} && systemctl restart {0}", unit.unit_name())))
+        .execute();
+    match res.result {
+        Some(ref result) if !result.success => Err(format!("Failed to start {}:\n\n{}", unit.unit_name(), result.stderr)),
+        _ => Ok(()),
+    }
+}
+
+// Root commands setting key in a service's env file, creating it private to root. The key=value
+// line comes in on stdin, keeping the value off the command line. The service sees it from its
+// next restart.
+pub fn set_env_var_script(name: &str, key: &str) -> String {
+    let path = env_file_path(name);
+    format!("line=$(cat) && mkdir -p {dir} && touch {path} && chmod 600 {path} && sed -i '/^{key}=/d' {path} && printf '%s\\n' \"$line\" >> {path}",
+            dir = ENV_DIR, path = path, key = key)
+}
+
+// Prints systemctl status for the service, returns whether it's running
+pub fn print_status(remote: &Remote, name: &str) -> bool {
+    let result = remote.run(&format!("systemctl status --no-pager --lines=10 {}", unit_name(name)));
+    println!("{}", result.stdout.trim_end());
+    // systemctl status exits 0 only for an active unit
+    result.success
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_a_minimal_unit() {
+        let unit = ServiceUnit::new("site", "npm start", "root");
+        assert_eq!(unit.render(), "\
+[Unit]
+Description=site (managed by breezyvps)
+After=network.target
+
+[Service]
+WorkingDirectory=/srv/site
+EnvironmentFile=-/etc/breezyvps/site.env
+ExecStart=/bin/sh -lc 'npm start'
+Restart=always
+
+[Install]
+WantedBy=multi-user.target
+");
+    }
+
+    #[test]
+    fn renders_user_port_and_env_file() {
+        let unit = ServiceUnit {
+            port: Some("3000".to_string()),
+            restart: "on-failure".to_string(),
+            ..ServiceUnit::new("api", "exec jekyll serve --port $PORT", "deploy")
+        };
+        let rendered = unit.render();
+        assert!(rendered.contains("User=deploy\n"));
+        assert!(rendered.contains("Environment=PORT=3000\n"));
+        assert!(rendered.contains("EnvironmentFile=-/etc/breezyvps/api.env\n"));
+        assert!(rendered.contains("ExecStart=/bin/sh -lc 'exec jekyll serve --port $$PORT'\n"));
+        assert!(rendered.contains("Restart=on-failure\n"));
+    }
+
+    #[test]
+    fn replaces_env_vars() {
+        assert_eq!(set_env_var_script("api", "DATABASE_URL"),
+                   "line=$(cat) && mkdir -p /etc/breezyvps && touch /etc/breezyvps/api.env && chmod 600 /etc/breezyvps/api.env && \
+sed -i '/^DATABASE_URL=/d' /etc/breezyvps/api.env && printf '%s\\n' \"$line\" >> /etc/breezyvps/api.env");
+    }
+}
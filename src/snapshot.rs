@@ -0,0 +1,182 @@
+use chrono::{DateTime, Utc};
+use super::command;
+use super::digitalocean;
+use super::digitalocean::{print_err, Droplet};
+
+// Snapshots and backups are both images as far as the API is concerned
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct Image {
+    pub id: u64,
+    pub name: String,
+    #[serde(default)]
+    pub created_at: String,
+    #[serde(default)]
+    pub size_gigabytes: f64,
+}
+
+pub fn list_snapshots(droplet: &Droplet) -> Result<Vec<Image>, String> {
+    digitalocean::doctl_json(&format!("compute droplet snapshots {}", droplet.id))
+}
+
+pub fn list_backups(droplet: &Droplet) -> Result<Vec<Image>, String> {
+    digitalocean::doctl_json(&format!("compute droplet backups {}", droplet.id))
+}
+
+fn created(image: &Image) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(&image.created_at).ok().map(|d| d.with_timezone(&Utc))
+}
+
+// Newest first
+fn sorted(images: &[Image]) -> Vec<&Image> {
+    let mut sorted: Vec<&Image> = images.iter().collect();
+    sorted.sort_by_key(|image| ::std::cmp::Reverse(created(image)));
+    sorted
+}
+
+// Everything but the keep most recent images. Images without a creation date we can read are
+// never pruned, there's no telling how old they are.
+pub fn snapshots_to_prune(images: &[Image], keep: usize) -> Vec<&Image> {
+    sorted(images).into_iter().filter(|image| created(image).is_some()).skip(keep).collect()
+}
+
+fn find_image<'a>(images: &'a [Image], selector: &str) -> Result<&'a Image, String> {
+    let matches: Vec<&Image> = images.iter()
+        .filter(|i| i.id.to_string() == selector || i.name == selector)
+        .collect();
+    match matches.len() {
+        1 => Ok(matches[0]),
+        0 => Err(format!("No snapshot or backup matches '{}'", selector)),
+        _ => Err(format!("'{}' matches more than one snapshot, use its ID", selector)),
+    }
+}
+
+fn print_images(images: &[Image]) {
+    for image in sorted(images) {
+        println!("\t{}\t{}\t{}GB\t{}", image.id, image.created_at, image.size_gigabytes, image.name);
+    }
+}
+
+pub fn print_snapshots(name: &str) {
+    print_err(digitalocean::require_droplet(name)
+        .and_then(|droplet| list_snapshots(&droplet))
+        .map(|images| print_images(&images)));
+}
+
+pub fn print_backups(name: &str) {
+    print_err(digitalocean::require_droplet(name)
+        .and_then(|droplet| list_backups(&droplet))
+        .map(|images| print_images(&images)));
+}
+
+pub fn create_snapshot(name: &str, snapshot_name: Option<&str>) {
+    print_err(digitalocean::require_droplet(name).and_then(|droplet| {
+        let snapshot_name = snapshot_name
+            .map(|s| s.to_string())
+            .unwrap_or_else(|| format!("{}-{}", name, Utc::now().format("%Y%m%d%H%M%S")));
+        digitalocean::snapshot_droplet(&droplet, &snapshot_name)
+    }));
+}
+
+// Restores the droplet's disk in place from one of its snapshots or backups
+pub fn restore_snapshot(name: &str, selector: &str, yes: bool) {
+    print_err(digitalocean::require_droplet(name).and_then(|droplet| {
+        digitalocean::refuse_protected(&droplet, "restore")?;
+        let mut images = list_snapshots(&droplet)?;
+        images.extend(list_backups(&droplet)?);
+        let image = find_image(&images, selector)?;
+        if !yes && !command::confirm(&format!("Restoring {} from {} replaces its disk, continue?", name, image.name)) {
+            return Err("Aborted".to_string());
+        }
+        digitalocean::run_droplet_action(&droplet, "restore", &format!("--image-id={}", image.id))
+    }));
+}
+
+fn delete_image(image: &Image) -> Result<(), String> {
+    let result = command::run_host_cmd(&format!("doctl compute image delete -f {}", image.id));
+    if !result.success {
+        return Err(format!("Failed to delete {}:\n{}", image.name, result.stderr));
+    }
+    println!("Deleted snapshot {} ({})", image.name, image.id);
+    Ok(())
+}
+
+pub fn delete_snapshot(name: &str, selector: &str, yes: bool) {
+    print_err(digitalocean::require_droplet(name).and_then(|droplet| {
+        let images = list_snapshots(&droplet)?;
+        let image = find_image(&images, selector)?;
+        if !yes && !command::confirm(&format!("Delete snapshot {}?", image.name)) {
+            return Err("Aborted".to_string());
+        }
+        delete_image(image)
+    }));
+}
+
+// Retention policy: keeps the newest keep snapshots of each droplet (every droplet when names is
+// empty) and deletes the rest. Never prompts, so it can run from cron.
+pub fn prune_snapshots(names: &[&str], keep: usize, dry_run: bool) {
+    let droplets = match digitalocean::list_droplets() {
+        Ok(droplets) => droplets,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+    for name in names {
+        if !droplets.iter().any(|d| d.name == *name) {
+            println!("No droplet named {}", name);
+        }
+    }
+    for droplet in droplets.iter().filter(|d| names.is_empty() || names.contains(&d.name.as_str())) {
+        let images = match list_snapshots(droplet) {
+            Ok(images) => images,
+            Err(e) => {
+                println!("{}", e);
+                continue;
+            }
+        };
+        for image in snapshots_to_prune(&images, keep) {
+            if dry_run {
+                println!("Would delete snapshot {} ({}) of {}", image.name, image.id, droplet.name);
+            } else {
+                print_err(delete_image(image));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(id: u64, created_at: &str) -> Image {
+        Image {
+            id,
+            name: format!("snap-{}", id),
+            created_at: created_at.to_string(),
+            size_gigabytes: 1.0,
+        }
+    }
+
+    #[test]
+    fn prunes_all_but_the_newest() {
+        let images = vec![
+            image(1, "2017-09-01T00:00:00Z"),
+            image(3, "2017-09-03T00:00:00Z"),
+            image(2, "2017-09-02T00:00:00Z"),
+        ];
+        let pruned: Vec<u64> = snapshots_to_prune(&images, 1).iter().map(|i| i.id).collect();
+        assert_eq!(pruned, vec![2, 1]);
+        assert!(snapshots_to_prune(&images, 5).is_empty());
+        let undated = vec![image(1, "2017-09-01T00:00:00Z"), image(2, ""), image(3, "2017-09-03T00:00:00Z")];
+        let pruned: Vec<u64> = snapshots_to_prune(&undated, 1).iter().map(|i| i.id).collect();
+        assert_eq!(pruned, vec![1]);
+    }
+
+    #[test]
+    fn finds_images_by_id_or_name() {
+        let images = vec![image(1, ""), image(2, "")];
+        assert_eq!(find_image(&images, "2").unwrap().id, 2);
+        assert_eq!(find_image(&images, "snap-1").unwrap().id, 1);
+        assert!(find_image(&images, "snap-3").is_err());
+    }
+}
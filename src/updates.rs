@@ -0,0 +1,208 @@
+use super::chain;
+use super::configure;
+use super::packages::PackageManager;
+use super::remote::Remote;
+
+const AUTO_UPGRADES_PATH: &str = "/etc/apt/apt.conf.d/20auto-upgrades";
+const UNATTENDED_PATH: &str = "/etc/apt/apt.conf.d/52breezyvps-unattended-upgrades";
+const TIMER_DROPIN_DIR: &str = "/etc/systemd/system/apt-daily-upgrade.timer.d";
+
+// apt-get without questions, keeping our changes to config files over the package's
+const APT_GET: &str = "DEBIAN_FRONTEND=noninteractive apt-get -y -o Dpkg::Options::=--force-confdef -o Dpkg::Options::=--force-confold";
+
+// When unattended upgrades run and may reboot, e.g. 03:00-05:00. May wrap past midnight.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RebootWindow {
+    // Minutes after midnight
+    pub start: u32,
+    pub minutes: u32,
+}
+
+fn parse_time(time: &str) -> Option<u32> {
+    let (hours, minutes) = time.trim().split_once(':')?;
+    let (hours, minutes): (u32, u32) = (hours.parse().ok()?, minutes.parse().ok()?);
+    if hours < 24 && minutes < 60 { Some(hours * 60 + minutes) } else { None }
+}
+
+impl RebootWindow {
+    pub fn parse(window: &str) -> Result<RebootWindow, String> {
+        let invalid = || format!("Invalid reboot window {}, expected HH:MM-HH:MM", window);
+        let (start, end) = window.split_once('-').ok_or_else(invalid)?;
+        let (start, end) = (parse_time(start).ok_or_else(invalid)?, parse_time(end).ok_or_else(invalid)?);
+        if start == end {
+            return Err(invalid());
+        }
+        Ok(RebootWindow { start, minutes: (end + 24 * 60 - start) % (24 * 60) })
+    }
+
+    // Moves apt-daily-upgrade into the window, at a random point so a fleet doesn't reboot at once
+    pub fn timer_dropin(&self) -> String {
+        format!("[Timer]\nOnCalendar=\nOnCalendar=*-*-* {:02}:{:02}\nRandomizedDelaySec={}m\n",
+                self.start / 60, self.start % 60, self.minutes)
+    }
+}
+
+pub fn auto_upgrades_config() -> String {
+    "APT::Periodic::Update-Package-Lists \"1\";\nAPT::Periodic::Unattended-Upgrade \"1\";\nAPT::Periodic::AutocleanInterval \"7\";\n".to_string()
+}
+
+// With a window, reboots right after the upgrade that needs it, which only ever runs inside the window
+pub fn unattended_config(window: Option<RebootWindow>) -> String {
+    let reboot = if window.is_some() {
+        "Unattended-Upgrade::Automatic-Reboot \"true\";\nUnattended-Upgrade::Automatic-Reboot-Time \"now\";\n"
+    } else {
+        "Unattended-Upgrade::Automatic-Reboot \"false\";\n"
+    };
+    format!("Unattended-Upgrade::Remove-Unused-Dependencies \"true\";\n{}", reboot)
+}
+
+// Everything here is apt specific
+fn require_apt(remote: &Remote) -> Result<(), String> {
+    match PackageManager::detect(remote)? {
+        PackageManager::Apt => Ok(()),
+        other => Err(format!("{} uses {:?}, only apt based hosts are supported", remote.host, other)),
+    }
+}
+
+// Installs unattended-upgrades for security updates, rebooting in window when one needs it
+pub fn configure_updates(remote: &Remote, window: Option<RebootWindow>) -> Result<(), String> {
+    require_apt(remote)?;
+    let auto = configure::write_local_file(&format!("{}-20auto-upgrades", remote.host), &auto_upgrades_config());
+    let unattended = configure::write_local_file(&format!("{}-unattended-upgrades", remote.host), &unattended_config(window));
+    let mut chain = chain::CommandChain::new()
+        .cmd(&remote.ssh_root(&format!("apt-get update && {} install unattended-upgrades", APT_GET)))
+        .cmd(&remote.scp_to_root(&auto, AUTO_UPGRADES_PATH, "644"))
+        .cmd(&remote.scp_to_root(&unattended, UNATTENDED_PATH, "644"))
+        .cmd(&format!("rm {} {}", auto, unattended));
+    let dropin = format!("{}/breezyvps.conf", TIMER_DROPIN_DIR);
+    chain = match window {
+        Some(window) => {
+            let local = configure::write_local_file(&format!("{}-apt-daily-upgrade.conf", remote.host), &window.timer_dropin());
+            chain
+                .cmd(&remote.ssh_root(&format!("mkdir -p {}", TIMER_DROPIN_DIR)))
+                .cmd(&remote.scp_to_root(&local, &dropin, "644"))
+                .cmd(&format!("rm {}", local))
+        },
+        None => chain.cmd(&remote.ssh_root(&format!("rm -f {}", dropin))),
+    };
+    let res = chain
+        .cmd(&remote.ssh_root("systemctl daemon-reload && systemctl restart apt-daily-upgrade.timer"))
+        .execute();
+    match res.result {
+        Some(ref result) if !result.success => Err(format!("Failed to configure updates:\n\n{}", result.stderr)),
+        _ => Ok(()),
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum PackageChange {
+    Upgraded { name: String, from: String, to: String },
+    Installed { name: String, version: String },
+    Removed { name: String, version: String },
+}
+
+// "name (version) ..." and the rest of the line after it
+fn name_and_version(rest: &str) -> Option<(String, String, &str)> {
+    let (name, rest) = rest.split_once(" (")?;
+    let (version, rest) = rest.split_once(')')?;
+    let name = name.split(':').next().unwrap_or(name);
+    Some((name.to_string(), version.to_string(), rest))
+}
+
+// Reads dpkg's Unpacking and Removing lines out of apt-get output
+pub fn parse_package_changes(output: &str) -> Vec<PackageChange> {
+    let mut changes = Vec::new();
+    for line in output.lines() {
+        if let Some(rest) = line.strip_prefix("Unpacking ") {
+            if let Some((name, to, rest)) = name_and_version(rest) {
+                let from = rest.trim().strip_prefix("over (").and_then(|r| r.split(')').next());
+                changes.push(match from {
+                    Some(from) => PackageChange::Upgraded { name, from: from.to_string(), to },
+                    None => PackageChange::Installed { name, version: to },
+                });
+            }
+        } else if let Some(rest) = line.strip_prefix("Removing ") {
+            if let Some((name, version, _)) = name_and_version(rest) {
+                changes.push(PackageChange::Removed { name, version });
+            }
+        }
+    }
+    changes
+}
+
+fn print_changes(changes: &[PackageChange]) {
+    if changes.is_empty() {
+        println!("Already up to date");
+    }
+    for change in changes {
+        match *change {
+            PackageChange::Upgraded { ref name, ref from, ref to } => println!("\tupgraded\t{}\t{} -> {}", name, from, to),
+            PackageChange::Installed { ref name, ref version } => println!("\tinstalled\t{}\t{}", name, version),
+            PackageChange::Removed { ref name, ref version } => println!("\tremoved\t{}\t{}", name, version),
+        }
+    }
+}
+
+// Full dist-upgrade, printing what changed and whether the host needs a reboot, rebooting
+// it when reboot is set. Returns whether a reboot was required.
+pub fn upgrade(remote: &Remote, reboot: bool) -> Result<bool, String> {
+    require_apt(remote)?;
+    let result = remote.run_root(&format!("apt-get update && {0} dist-upgrade && {0} autoremove", APT_GET));
+    if !result.success {
+        return Err(format!("Upgrade failed:\n\n{}", result.stderr));
+    }
+    print_changes(&parse_package_changes(&result.stdout));
+
+    let required = remote.run("cat /var/run/reboot-required.pkgs 2>/dev/null || test -f /var/run/reboot-required");
+    if !required.success {
+        println!("No reboot required");
+        return Ok(false);
+    }
+    let packages: Vec<&str> = required.stdout.lines().collect();
+    println!("Reboot required{}", if packages.is_empty() { String::new() } else { format!(" by {}", packages.join(", ")) });
+    if reboot {
+        info!("Rebooting {}", remote.host);
+        // The connection drops as the host goes down, so the exit status means nothing
+        let _ = remote.run_root("systemctl reboot");
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_reboot_windows() {
+        assert_eq!(RebootWindow::parse("03:00-05:30"), Ok(RebootWindow { start: 180, minutes: 150 }));
+        assert_eq!(RebootWindow::parse("23:00-01:00"), Ok(RebootWindow { start: 1380, minutes: 120 }));
+        assert!(RebootWindow::parse("03:00").is_err());
+        assert!(RebootWindow::parse("25:00-01:00").is_err());
+        assert!(RebootWindow::parse("03:00-03:00").is_err());
+        assert_eq!(RebootWindow::parse("23:00-01:00").unwrap().timer_dropin(),
+                   "[Timer]\nOnCalendar=\nOnCalendar=*-*-* 23:00\nRandomizedDelaySec=120m\n");
+    }
+
+    #[test]
+    fn only_reboots_with_a_window() {
+        assert!(unattended_config(None).contains("Automatic-Reboot \"false\""));
+        assert!(unattended_config(Some(RebootWindow { start: 180, minutes: 60 })).contains("Automatic-Reboot \"true\""));
+    }
+
+    #[test]
+    fn reads_package_changes_from_dpkg_output() {
+        let output = "\
+Preparing to unpack .../libssl1.1_1.1.1f-1ubuntu2.20_amd64.deb ...
+Unpacking libssl1.1:amd64 (1.1.1f-1ubuntu2.20) over (1.1.1f-1ubuntu2.19) ...
+Selecting previously unselected package linux-image-5.4.0-150-generic.
+Unpacking linux-image-5.4.0-150-generic (5.4.0-150.167) ...
+Removing linux-image-5.4.0-100-generic (5.4.0-100.113) ...
+Setting up libssl1.1:amd64 (1.1.1f-1ubuntu2.20) ...
+";
+        assert_eq!(parse_package_changes(output), vec![
+            PackageChange::Upgraded { name: "libssl1.1".to_string(), from: "1.1.1f-1ubuntu2.19".to_string(), to: "1.1.1f-1ubuntu2.20".to_string() },
+            PackageChange::Installed { name: "linux-image-5.4.0-150-generic".to_string(), version: "5.4.0-150.167".to_string() },
+            PackageChange::Removed { name: "linux-image-5.4.0-100-generic".to_string(), version: "5.4.0-100.113".to_string() },
+        ]);
+    }
+}